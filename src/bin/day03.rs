@@ -1,23 +1,153 @@
-use std::{fs, collections::HashSet};
+use anyhow::{anyhow, bail, Context};
+use clap::Parser;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::PathBuf,
+    str::FromStr,
+};
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Parser)]
+struct Args {
+    /// Path to the puzzle input
+    #[arg(long, default_value = "src/inputs/day03.txt")]
+    input: PathBuf,
+
+    /// Item mapping file with one `<glyph> [priority]` entry per line. A
+    /// glyph without a priority gets the one following the previous entry
+    #[arg(long)]
+    alphabet: Option<PathBuf>,
+
+    /// Re-form the elves into groups instead of taking them in input order
+    #[arg(long, value_enum)]
+    form_groups: Option<Objective>,
+
+    /// Number of elves per group when re-forming groups
+    #[arg(long, default_value_t = 3)]
+    group_size: usize,
+
+    /// Give up on proving the formation optimal after this many search nodes
+    #[arg(long, default_value_t = 100_000)]
+    node_limit: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 struct Item {
+    glyph: char,
     priority: u32,
 }
 
-impl Item {
-    fn from(c: char) -> Option<Item> {
-       let priority = match c {
-           // This relates the ASCII value of chars to the mapping we want
-           // which is lowercase a is 1 up to uppercase Z being 52
-           'a'..='z' => Some(c as u32 - 96),
-           'A'..='Z' => Some(c as u32 - 38),
-           _ => None
-       }?; 
-       
-       Some(Item {
-           priority
-       })
+/// Maps item glyphs to their priorities. Anything not in the alphabet is
+/// rejected rather than skipped
+#[derive(Debug, Clone)]
+struct ItemAlphabet {
+    priorities: HashMap<char, u32>,
+}
+
+impl Default for ItemAlphabet {
+    /// The puzzle's mapping, lowercase a is 1 up to uppercase Z being 52
+    fn default() -> Self {
+        ItemAlphabet::builder()
+            .glyphs('a'..='z')
+            .glyphs('A'..='Z')
+            .build()
+    }
+}
+
+impl ItemAlphabet {
+    fn builder() -> ItemAlphabetBuilder {
+        ItemAlphabetBuilder {
+            priorities: HashMap::new(),
+            next_priority: Some(1),
+        }
+    }
+
+    fn item(&self, glyph: char) -> Result<Item, anyhow::Error> {
+        match self.priorities.get(&glyph) {
+            Some(&priority) => Ok(Item { glyph, priority }),
+            None => Err(anyhow!("Unknown item {:?}", glyph)),
+        }
+    }
+}
+
+impl FromStr for ItemAlphabet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut builder = ItemAlphabet::builder();
+
+        for (number, line) in s.lines().enumerate() {
+            let mut it = line.split_whitespace();
+
+            let glyph = match it.next() {
+                Some(glyph) => glyph,
+                None => continue,
+            };
+
+            let mut chars = glyph.chars();
+            let glyph = match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => bail!("Line {}: {:?} is not a single glyph", number + 1, glyph),
+            };
+
+            if builder.priorities.contains_key(&glyph) {
+                bail!("Line {}: {:?} is mapped more than once", number + 1, glyph);
+            }
+
+            builder = match it.next() {
+                Some(priority) => {
+                    let priority = priority
+                        .parse::<u32>()
+                        .with_context(|| format!("Line {}: invalid priority", number + 1))?;
+                    builder.glyph_with_priority(glyph, priority)
+                }
+                None if builder.next_priority.is_none() => bail!(
+                    "Line {}: {:?} has no priority left after {}",
+                    number + 1,
+                    glyph,
+                    u32::MAX
+                ),
+                None => builder.glyph(glyph),
+            };
+        }
+
+        if builder.priorities.is_empty() {
+            bail!("Item alphabet is empty");
+        }
+
+        Ok(builder.build())
+    }
+}
+
+#[derive(Debug)]
+struct ItemAlphabetBuilder {
+    priorities: HashMap<char, u32>,
+    /// None once the most recent priority was the largest possible
+    next_priority: Option<u32>,
+}
+
+impl ItemAlphabetBuilder {
+    /// Maps the glyph to the priority after the most recently added one.
+    /// Panics if that was `u32::MAX`
+    fn glyph(self, glyph: char) -> Self {
+        let priority = self.next_priority.expect("No priority follows u32::MAX");
+        self.glyph_with_priority(glyph, priority)
+    }
+
+    fn glyphs(self, glyphs: impl IntoIterator<Item = char>) -> Self {
+        glyphs.into_iter().fold(self, |builder, c| builder.glyph(c))
+    }
+
+    fn glyph_with_priority(mut self, glyph: char, priority: u32) -> Self {
+        self.priorities.insert(glyph, priority);
+        self.next_priority = priority.checked_add(1);
+        self
+    }
+
+    fn build(self) -> ItemAlphabet {
+        ItemAlphabet {
+            priorities: self.priorities,
+        }
     }
 }
 
@@ -27,18 +157,13 @@ struct Compartment {
 }
 
 impl Compartment {
-    fn from(s: &str) -> Option<Compartment> {
-        let items = s
-            .chars()
-            .filter_map(|c| Item::from(c))
-            .collect::<Vec<Item>>();
-   
-        if items.len() == 0 {
-            return None
+    fn from(items: &[Item]) -> Option<Compartment> {
+        if items.is_empty() {
+            return None;
         }
 
         Some(Compartment {
-            items
+            items: items.to_vec(),
         })
     }
 
@@ -54,24 +179,39 @@ struct Rucksack {
 }
 
 impl Rucksack {
-    fn from(s: &str) -> Option<Rucksack> {
-        let compartment_split = s.len()/2;
-        let first_compartment = Compartment::from(&s[0..compartment_split])?;
-        let second_compartment = Compartment::from(&s[compartment_split..])?;
+    fn from(s: &str, alphabet: &ItemAlphabet) -> Result<Rucksack, anyhow::Error> {
+        let items = s
+            .chars()
+            .map(|c| alphabet.item(c))
+            .collect::<Result<Vec<Item>, _>>()?;
+
+        if items.len() % 2 != 0 {
+            bail!("Rucksack {:?} has an odd number of items", s);
+        }
+
+        let compartment_split = items.len() / 2;
+        let (first_compartment, second_compartment) = match (
+            Compartment::from(&items[..compartment_split]),
+            Compartment::from(&items[compartment_split..]),
+        ) {
+            (Some(first), Some(second)) => (first, second),
+            _ => bail!("Rucksack {:?} has an empty compartment", s),
+        };
 
-        Some(Rucksack {
+        Ok(Rucksack {
             first_compartment,
-            second_compartment
+            second_compartment,
         })
     }
 
     fn priorities_in_both_compartments(&self) -> Vec<u32> {
-        self.first_compartment.items
+        self.first_compartment
+            .items
             .iter()
             .filter(|&i| self.second_compartment.has_item(i))
-            .map(|i| i.priority)
             .collect::<HashSet<_>>()
             .into_iter()
+            .map(|i| i.priority)
             .collect::<Vec<u32>>()
     }
 
@@ -86,13 +226,17 @@ impl Rucksack {
 
     fn has_item(&self, item: &Item) -> bool {
         self.first_compartment.items.contains(item) || self.second_compartment.items.contains(item)
-    } 
+    }
+
+    fn item_types(&self) -> HashSet<Item> {
+        self.inventory().into_iter().collect()
+    }
 }
 
 //
 #[derive(Debug)]
 struct Group {
-    rucksacks: Vec<Rucksack>
+    rucksacks: Vec<Rucksack>,
 }
 
 impl Group {
@@ -100,47 +244,353 @@ impl Group {
         if self.rucksacks.len() != 3 {
             return None;
         }
-        
+
         let first = &self.rucksacks[0];
-        let second = &self.rucksacks[1];    
+        let second = &self.rucksacks[1];
         let third = &self.rucksacks[2];
 
-        Some(first.inventory() 
-            .into_iter()
-            .filter(|item| second.has_item(item))
-            .filter(|item| third.has_item(item))
-            .map(|item| item.priority)
-            .collect::<HashSet<_>>()
+        Some(
+            first
+                .inventory()
+                .into_iter()
+                .filter(|item| second.has_item(item))
+                .filter(|item| third.has_item(item))
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .map(|item| item.priority)
+                .collect::<Vec<u32>>(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Objective {
+    Maximise,
+    Minimise,
+}
+
+impl Objective {
+    fn is_better(&self, candidate: u64, current: u64) -> bool {
+        match self {
+            Objective::Maximise => candidate > current,
+            Objective::Minimise => candidate < current,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FormedGroup {
+    members: Vec<usize>,
+    badge: Item,
+}
+
+#[derive(Debug, Clone)]
+struct GroupFormation {
+    groups: Vec<FormedGroup>,
+    total_priority: u64,
+    proven_optimal: bool,
+}
+
+#[derive(Debug)]
+enum GroupSearchOutcome {
+    Found(GroupFormation),
+    Infeasible,
+    /// The node limit was reached before any valid partition was found
+    Undecided,
+}
+
+/// Branch and bound search for a partition of the elves into groups of
+/// `group_size` where every group shares exactly one item type, its badge.
+/// This is an exact cover problem, so it always branches on the first elf
+/// still without a group, trying each group it could join. Groups are
+/// built up one member at a time as the search goes, never listed up
+/// front, and every member added counts against the node limit
+#[derive(Debug)]
+struct GroupSolver {
+    item_types: Vec<HashSet<Item>>,
+    limits: Vec<u32>,
+    group_size: usize,
+    objective: Objective,
+    node_limit: usize,
+    nodes: usize,
+    best: Option<(Vec<FormedGroup>, u64)>,
+}
+
+impl GroupSolver {
+    fn new(
+        rucksacks: &[Rucksack],
+        group_size: usize,
+        objective: Objective,
+        node_limit: usize,
+    ) -> GroupSolver {
+        let item_types = rucksacks.iter().map(|r| r.item_types()).collect::<Vec<_>>();
+
+        let limits = item_types
+            .iter()
+            .map(|items| {
+                let priorities = items.iter().map(|i| i.priority);
+                match objective {
+                    Objective::Maximise => priorities.max().unwrap_or(0),
+                    Objective::Minimise => priorities.min().unwrap_or(0),
+                }
+            })
+            .collect();
+
+        GroupSolver {
+            item_types,
+            limits,
+            group_size,
+            objective,
+            node_limit,
+            nodes: 0,
+            best: None,
+        }
+    }
+
+    fn solve(mut self) -> GroupSearchOutcome {
+        let elves = self.item_types.len();
+
+        if self.group_size == 0 || !elves.is_multiple_of(self.group_size) {
+            return GroupSearchOutcome::Infeasible;
+        }
+
+        let mut assigned = vec![false; elves];
+        let mut groups = vec![];
+        let completed = self.search(&mut assigned, &mut groups, 0);
+
+        match self.best {
+            Some((groups, total_priority)) => GroupSearchOutcome::Found(GroupFormation {
+                groups,
+                total_priority,
+                proven_optimal: completed,
+            }),
+            None if completed => GroupSearchOutcome::Infeasible,
+            None => GroupSearchOutcome::Undecided,
+        }
+    }
+
+    /// Counts a search node, returning false once over the limit
+    fn visit(&mut self) -> bool {
+        self.nodes += 1;
+        self.nodes <= self.node_limit
+    }
+
+    /// Returns false if the search was cut short by the node limit
+    fn search(
+        &mut self,
+        assigned: &mut Vec<bool>,
+        groups: &mut Vec<FormedGroup>,
+        total: u64,
+    ) -> bool {
+        if !self.visit() {
+            return false;
+        }
+
+        let elf = match assigned.iter().position(|&a| !a) {
+            Some(elf) => elf,
+            None => {
+                let improves = match &self.best {
+                    Some((_, best)) => self.objective.is_better(total, *best),
+                    None => true,
+                };
+                if improves {
+                    self.best = Some((groups.clone(), total));
+                }
+                return true;
+            }
+        };
+
+        if let Some((_, best)) = &self.best {
+            if !self
+                .objective
+                .is_better(self.bound(assigned) + total, *best)
+            {
+                return true;
+            }
+        }
+
+        let shared = self.item_types[elf].clone();
+        self.extend_group(assigned, &mut vec![elf], shared, elf + 1, groups, total)
+    }
+
+    /// Tries every group of elves still without one that contains
+    /// `members`, whose remaining members all have an index of at least
+    /// `from`, searching on from each as soon as it is complete. Returns
+    /// false if the search was cut short by the node limit
+    fn extend_group(
+        &mut self,
+        assigned: &mut Vec<bool>,
+        members: &mut Vec<usize>,
+        shared: HashSet<Item>,
+        from: usize,
+        groups: &mut Vec<FormedGroup>,
+        total: u64,
+    ) -> bool {
+        if members.len() == self.group_size {
+            if shared.len() != 1 {
+                return true;
+            }
+
+            let badge = *shared.iter().next().unwrap();
+            for &member in members.iter() {
+                assigned[member] = true;
+            }
+            groups.push(FormedGroup {
+                members: members.clone(),
+                badge,
+            });
+
+            let completed = self.search(assigned, groups, total + u64::from(badge.priority));
+
+            groups.pop();
+            for &member in members.iter() {
+                assigned[member] = false;
+            }
+
+            return completed;
+        }
+
+        for elf in from..self.item_types.len() {
+            if assigned[elf] {
+                continue;
+            }
+
+            let narrowed = shared
+                .intersection(&self.item_types[elf])
+                .copied()
+                .collect::<HashSet<_>>();
+
+            if narrowed.is_empty() {
+                continue;
+            }
+
+            if !self.visit() {
+                return false;
+            }
+
+            members.push(elf);
+            let completed = self.extend_group(assigned, members, narrowed, elf + 1, groups, total);
+            members.pop();
+
+            if !completed {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Most optimistic total the unassigned elves could still contribute. A
+    /// badge is carried by every member, so it is bounded by the weakest
+    /// member's best item when maximising and by the strongest member's
+    /// worst item when minimising
+    fn bound(&self, assigned: &[bool]) -> u64 {
+        let mut limits = self
+            .limits
+            .iter()
+            .zip(assigned)
+            .filter(|(_, &a)| !a)
+            .map(|(&limit, _)| limit)
+            .collect::<Vec<u32>>();
+
+        limits.sort();
+        if self.objective == Objective::Maximise {
+            limits.reverse();
+        }
+
+        limits
             .into_iter()
-            .collect::<Vec<u32>>())
+            .skip(self.group_size - 1)
+            .step_by(self.group_size)
+            .map(u64::from)
+            .sum()
     }
 }
 
-pub fn main() {
-    let data = fs::read_to_string("src/inputs/day03.txt")
-        .expect("Should have been able to read input")
-        .split("\n")
-        .filter_map(|s| Rucksack::from(s))
-        .collect::<Vec<Rucksack>>();
+pub fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse();
+
+    let alphabet = match &args.alphabet {
+        Some(path) => fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read {}", path.display()))?
+            .parse::<ItemAlphabet>()?,
+        None => ItemAlphabet::default(),
+    };
+
+    let data = fs::read_to_string(&args.input)
+        .with_context(|| format!("Couldn't read {}", args.input.display()))?
+        .lines()
+        .enumerate()
+        .filter(|(_, s)| !s.is_empty())
+        .map(|(number, s)| {
+            Rucksack::from(s, &alphabet).map_err(|e| e.context(format!("Line {}", number + 1)))
+        })
+        .collect::<Result<Vec<Rucksack>, _>>()?;
 
-    let priority_sum_total = data 
+    // A mapping file may give priorities up to u32::MAX, so totals are
+    // added up in u64
+    let priority_sum_total = data
         .iter()
-        .map(|rucksack| 
-             rucksack
-             .priorities_in_both_compartments()
-             .into_iter()
-             .sum::<u32>())
-        .into_iter()
-        .sum::<u32>();
-    
-    println!("Sum of priorities in both compartments: {}", priority_sum_total);
-
-    let group_priority_sum_total = data 
+        .flat_map(|rucksack| rucksack.priorities_in_both_compartments())
+        .map(u64::from)
+        .sum::<u64>();
+
+    println!(
+        "Sum of priorities in both compartments: {}",
+        priority_sum_total
+    );
+
+    let group_priority_sum_total = data
         .chunks(3)
-        .map(|chunk| Group {rucksacks: chunk.to_vec()})
+        .map(|chunk| Group {
+            rucksacks: chunk.to_vec(),
+        })
         .filter_map(|group| group.shared_items_priorities())
-        .map(|priorities|  priorities.into_iter().sum::<u32>())
-        .sum::<u32>();
+        .flatten()
+        .map(u64::from)
+        .sum::<u64>();
+
+    println!(
+        "Sum of priorities for all groups total: {}",
+        group_priority_sum_total
+    );
+
+    if let Some(objective) = args.form_groups {
+        let solver = GroupSolver::new(&data, args.group_size, objective, args.node_limit);
+
+        match solver.solve() {
+            GroupSearchOutcome::Found(formation) => {
+                for group in formation.groups.iter() {
+                    let members = group
+                        .members
+                        .iter()
+                        .map(|elf| (elf + 1).to_string())
+                        .collect::<Vec<String>>()
+                        .join(", ");
+                    println!(
+                        "Elves {} share badge {} ({})",
+                        members, group.badge.glyph, group.badge.priority
+                    );
+                }
+                println!(
+                    "Sum of badge priorities for re-formed groups: {}{}",
+                    formation.total_priority,
+                    if formation.proven_optimal {
+                        ""
+                    } else {
+                        " (node limit reached, may not be optimal)"
+                    }
+                );
+            }
+            GroupSearchOutcome::Infeasible => {
+                println!("No partition into groups of {} exists", args.group_size)
+            }
+            GroupSearchOutcome::Undecided => println!(
+                "No partition into groups of {} found within {} nodes",
+                args.group_size, args.node_limit
+            ),
+        }
+    }
 
-    println!("Sum of priorities for all groups total: {}", group_priority_sum_total);
+    Ok(())
 }
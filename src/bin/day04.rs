@@ -1,7 +1,50 @@
-use anyhow;
-use std::str::FromStr;
+use anyhow::{bail, ensure};
+use clap::Parser;
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fmt::{self, Display},
+    str::FromStr,
+};
 
-#[derive(Debug)]
+#[derive(Debug, Parser)]
+struct Args {
+    /// Print the span, common sections and overlapping members of each group
+    #[arg(long)]
+    groups: bool,
+
+    /// List the elves, anywhere in the camp, assigned to this section
+    #[arg(long)]
+    elves_at: Option<usize>,
+
+    /// List every pair of elves in the camp whose assignments overlap
+    #[arg(long)]
+    list_overlaps: bool,
+
+    /// Print a run-length summary of how many elves cover each section
+    #[arg(long)]
+    coverage: bool,
+
+    /// Include a strip chart of every assignment with the coverage summary
+    #[arg(long, requires = "coverage")]
+    chart: bool,
+
+    /// Suggest how to change assignments so that no two elves overlap
+    #[arg(long, value_enum)]
+    resolve: Option<ResolveStrategy>,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum ResolveStrategy {
+    /// Change as few assignments as possible
+    FewestAssignments,
+    /// Remove as few sections as possible
+    FewestSections,
+}
+
+/// An inclusive range of section IDs. Any assignment whose lower bound is
+/// above its upper bound is empty, and all empty assignments compare equal
+#[derive(Debug, Clone, Copy)]
 struct SectionAssignment {
     lower: usize,
     upper: usize,
@@ -13,81 +56,1029 @@ impl FromStr for SectionAssignment {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let (lower, upper) = match s.split_once("-") {
             Some((lower, upper)) => (lower, upper),
-            None => unreachable!("Invalid input"),
+            None => bail!("{:?} is not a range", s),
         };
 
         let lower = lower.parse::<usize>()?;
         let upper = upper.parse::<usize>()?;
 
+        // Only the interval algebra produces empty assignments, an elf is
+        // always given at least one section
+        ensure!(lower <= upper, "{:?} is a reversed range", s);
+
         Ok(SectionAssignment { lower, upper })
     }
 }
 
+impl Display for SectionAssignment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            write!(f, "empty")
+        } else if self.lower == self.upper {
+            write!(f, "{}", self.lower)
+        } else {
+            write!(f, "{}-{}", self.lower, self.upper)
+        }
+    }
+}
+
+impl PartialEq for SectionAssignment {
+    fn eq(&self, other: &Self) -> bool {
+        (self.is_empty() && other.is_empty())
+            || (self.lower == other.lower && self.upper == other.upper)
+    }
+}
+
+impl Eq for SectionAssignment {}
+
 impl SectionAssignment {
-    fn fully_contained_in(&self, other: &SectionAssignment) -> bool {
-        self.lower >= other.lower && self.upper <= other.upper
+    fn new(lower: usize, upper: usize) -> SectionAssignment {
+        SectionAssignment { lower, upper }
     }
 
-    fn partially_contained_in(&self, other: &SectionAssignment) -> bool {
-        (self.lower >= other.lower && self.lower <= other.upper)
-            || (self.upper <= other.upper && self.lower >= other.lower)
+    fn empty() -> SectionAssignment {
+        SectionAssignment::new(1, 0)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.lower > self.upper
+    }
+
+    fn len(&self) -> usize {
+        if self.is_empty() {
+            0
+        } else {
+            self.upper - self.lower + 1
+        }
+    }
+
+    fn contains_section(&self, section: usize) -> bool {
+        self.lower <= section && section <= self.upper
+    }
+
+    /// Whether every section of `other` is also in `self`
+    fn contains(&self, other: &SectionAssignment) -> bool {
+        other.is_empty() || (self.lower <= other.lower && other.upper <= self.upper)
+    }
+
+    fn overlaps(&self, other: &SectionAssignment) -> bool {
+        !self.intersection(other).is_empty()
+    }
+
+    fn intersection(&self, other: &SectionAssignment) -> SectionAssignment {
+        let intersection =
+            SectionAssignment::new(self.lower.max(other.lower), self.upper.min(other.upper));
+
+        if intersection.is_empty() {
+            SectionAssignment::empty()
+        } else {
+            intersection
+        }
+    }
+
+    /// The union of the two assignments, provided it is itself a single
+    /// range, i.e. they overlap or are directly adjacent
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn union(&self, other: &SectionAssignment) -> Option<SectionAssignment> {
+        if self.is_empty() {
+            return Some(*other);
+        }
+        if other.is_empty() {
+            return Some(*self);
+        }
+
+        let (first, second) = if self.lower <= other.lower {
+            (self, other)
+        } else {
+            (other, self)
+        };
+
+        if second.lower > first.upper + 1 {
+            return None;
+        }
+
+        Some(SectionAssignment::new(
+            first.lower,
+            first.upper.max(second.upper),
+        ))
+    }
+
+    /// The sections of `self` not in `other`, as at most two ranges in
+    /// ascending order
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn difference(&self, other: &SectionAssignment) -> Vec<SectionAssignment> {
+        let overlap = self.intersection(other);
+        if overlap.is_empty() {
+            return if self.is_empty() { vec![] } else { vec![*self] };
+        }
+
+        let mut pieces = vec![];
+        if self.lower < overlap.lower {
+            pieces.push(SectionAssignment::new(self.lower, overlap.lower - 1));
+        }
+        if overlap.upper < self.upper {
+            pieces.push(SectionAssignment::new(overlap.upper + 1, self.upper));
+        }
+
+        pieces
     }
 }
 
+/// The assignments on one line of the input, one per elf in the crew
 #[derive(Debug)]
-struct AssignmentPair {
-    first: SectionAssignment,
-    second: SectionAssignment,
+struct AssignmentGroup {
+    members: Vec<SectionAssignment>,
 }
 
-impl FromIterator<SectionAssignment> for AssignmentPair {
+impl FromIterator<SectionAssignment> for AssignmentGroup {
     fn from_iter<T: IntoIterator<Item = SectionAssignment>>(iter: T) -> Self {
-        let mut it = iter.into_iter();
-        let first = it.next();
-        let second = it.next();
+        AssignmentGroup {
+            members: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl AssignmentGroup {
+    /// Whether any member's assignment is fully contained in another's
+    fn has_contained_member(&self) -> bool {
+        self.members.iter().enumerate().any(|(i, a)| {
+            self.members
+                .iter()
+                .enumerate()
+                .any(|(j, b)| i != j && b.contains(a))
+        })
+    }
+
+    /// Indices of every pair of members whose assignments overlap
+    fn overlapping_members(&self) -> Vec<(usize, usize)> {
+        let mut overlapping = vec![];
+
+        for (i, a) in self.members.iter().enumerate() {
+            for (j, b) in self.members.iter().enumerate().skip(i + 1) {
+                if a.overlaps(b) {
+                    overlapping.push((i, j));
+                }
+            }
+        }
+
+        overlapping
+    }
+
+    /// The sections every member is assigned to
+    fn common_intersection(&self) -> SectionAssignment {
+        match self.members.split_first() {
+            Some((first, rest)) => rest.iter().fold(*first, |common, a| common.intersection(a)),
+            None => SectionAssignment::empty(),
+        }
+    }
+
+    /// The smallest range covering every member's assignment
+    fn union_span(&self) -> SectionAssignment {
+        let assigned = self.members.iter().filter(|a| !a.is_empty());
+
+        match (
+            assigned.clone().map(|a| a.lower).min(),
+            assigned.map(|a| a.upper).max(),
+        ) {
+            (Some(lower), Some(upper)) => SectionAssignment::new(lower, upper),
+            _ => SectionAssignment::empty(),
+        }
+    }
+}
+
+/// Identifies an elf by the line of its group and its position on that
+/// line, both zero based
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct ElfId {
+    group: usize,
+    member: usize,
+}
+
+impl Display for ElfId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.group + 1, self.member + 1)
+    }
+}
+
+/// Every non-empty assignment in the camp along with the elf it belongs to
+fn camp_assignments(assignment_groups: &[AssignmentGroup]) -> Vec<(ElfId, SectionAssignment)> {
+    assignment_groups
+        .iter()
+        .enumerate()
+        .flat_map(|(group, assignment_group)| {
+            assignment_group
+                .members
+                .iter()
+                .enumerate()
+                .map(move |(member, &assignment)| (ElfId { group, member }, assignment))
+        })
+        .filter(|(_, assignment)| !assignment.is_empty())
+        .collect()
+}
+
+#[derive(Debug)]
+struct IntervalNode {
+    center: usize,
+    /// Assignments spanning `center`, ascending by lower bound
+    by_lower: Vec<usize>,
+    /// The same assignments, descending by upper bound
+    by_upper: Vec<usize>,
+    left: Option<usize>,
+    right: Option<usize>,
+}
+
+/// A centered interval tree over every non-empty assignment in the camp
+#[derive(Debug)]
+struct SectionIndex {
+    assignments: Vec<(ElfId, SectionAssignment)>,
+    nodes: Vec<IntervalNode>,
+    root: Option<usize>,
+}
+
+impl SectionIndex {
+    fn from(assignment_groups: &[AssignmentGroup]) -> SectionIndex {
+        let mut index = SectionIndex {
+            assignments: camp_assignments(assignment_groups),
+            nodes: vec![],
+            root: None,
+        };
+
+        let all = (0..index.assignments.len()).collect::<Vec<usize>>();
+        index.root = index.build(all);
+
+        index
+    }
+
+    fn build(&mut self, members: Vec<usize>) -> Option<usize> {
+        if members.is_empty() {
+            return None;
+        }
+
+        let mut endpoints = members
+            .iter()
+            .flat_map(|&i| [self.assignments[i].1.lower, self.assignments[i].1.upper])
+            .collect::<Vec<usize>>();
+        endpoints.sort_unstable();
+        let center = endpoints[endpoints.len() / 2];
+
+        let (mut left, mut right, mut spanning) = (vec![], vec![], vec![]);
+        for i in members {
+            let assignment = self.assignments[i].1;
+            if assignment.upper < center {
+                left.push(i);
+            } else if assignment.lower > center {
+                right.push(i);
+            } else {
+                spanning.push(i);
+            }
+        }
+
+        let mut by_lower = spanning.clone();
+        by_lower.sort_by_key(|&i| self.assignments[i].1.lower);
+        let mut by_upper = spanning;
+        by_upper.sort_by_key(|&i| Reverse(self.assignments[i].1.upper));
+
+        let left = self.build(left);
+        let right = self.build(right);
+
+        self.nodes.push(IntervalNode {
+            center,
+            by_lower,
+            by_upper,
+            left,
+            right,
+        });
+
+        Some(self.nodes.len() - 1)
+    }
+
+    /// Every elf assigned to `section`, in O(log n + k)
+    fn elves_at(&self, section: usize) -> Vec<ElfId> {
+        let mut elves = vec![];
+        let mut current = self.root;
+
+        while let Some(index) = current {
+            let node = &self.nodes[index];
+
+            if section < node.center {
+                elves.extend(
+                    node.by_lower
+                        .iter()
+                        .take_while(|&&i| self.assignments[i].1.lower <= section)
+                        .map(|&i| self.assignments[i].0),
+                );
+                current = node.left;
+            } else if section > node.center {
+                elves.extend(
+                    node.by_upper
+                        .iter()
+                        .take_while(|&&i| self.assignments[i].1.upper >= section)
+                        .map(|&i| self.assignments[i].0),
+                );
+                current = node.right;
+            } else {
+                elves.extend(node.by_lower.iter().map(|&i| self.assignments[i].0));
+                current = None;
+            }
+        }
+
+        elves.sort();
+        elves
+    }
+
+    /// Every pair of elves whose assignments overlap, found with a sweep over
+    /// the lower bounds in O(n log n + k)
+    fn overlapping_pairs(&self) -> Vec<(ElfId, ElfId)> {
+        let mut order = (0..self.assignments.len()).collect::<Vec<usize>>();
+        order.sort_by_key(|&i| self.assignments[i].1.lower);
+
+        let mut active: BinaryHeap<Reverse<(usize, usize)>> = BinaryHeap::new();
+        let mut pairs = vec![];
+
+        for i in order {
+            let (elf, assignment) = self.assignments[i];
+
+            while let Some(&Reverse((upper, _))) = active.peek() {
+                if upper >= assignment.lower {
+                    break;
+                }
+                active.pop();
+            }
+
+            pairs.extend(active.iter().map(|&Reverse((_, j))| {
+                (
+                    self.assignments[j].0.min(elf),
+                    self.assignments[j].0.max(elf),
+                )
+            }));
+            active.push(Reverse((assignment.upper, i)));
+        }
+
+        pairs.sort();
+        pairs
+    }
+
+    /// How many pairs `overlapping_pairs` would find, without listing them.
+    /// Taking assignments by lower bound, each overlaps every one before it
+    /// except those that ended before it started, and everything ending
+    /// that early must have started earlier too
+    fn count_overlapping_pairs(&self) -> usize {
+        let mut lowers = self
+            .assignments
+            .iter()
+            .map(|(_, a)| a.lower)
+            .collect::<Vec<usize>>();
+        let mut uppers = self
+            .assignments
+            .iter()
+            .map(|(_, a)| a.upper)
+            .collect::<Vec<usize>>();
+        lowers.sort();
+        uppers.sort();
+
+        lowers
+            .iter()
+            .enumerate()
+            .map(|(before, &lower)| before - uppers.partition_point(|&upper| upper < lower))
+            .sum()
+    }
+
+    /// The largest number of elves assigned to a single section, along with
+    /// the lowest section where that happens
+    fn max_coverage(&self) -> Option<(usize, usize)> {
+        // Ends sort before starts at the same position since an assignment
+        // ending at `upper` stops covering at `upper + 1`
+        let mut events = self
+            .assignments
+            .iter()
+            .flat_map(|(_, a)| [(a.lower, 1), (a.upper + 1, -1)])
+            .collect::<Vec<(usize, i64)>>();
+        events.sort();
+
+        let mut coverage = 0;
+        let mut best: Option<(usize, usize)> = None;
+
+        for (section, change) in events {
+            coverage += change;
+            if best.is_none_or(|(count, _)| coverage as usize > count) {
+                best = Some((coverage as usize, section));
+            }
+        }
+
+        best
+    }
+}
 
-        let (first, second) = match (first, second) {
-            (Some(first), Some(second)) => (first, second),
-            _ => unreachable!("Should not happen, input must be very wrong"),
+/// How many elves cover each section between the lowest and highest
+/// assigned sections in the camp, as maximal runs of sections with the same
+/// coverage
+#[derive(Debug)]
+struct CoverageReport {
+    runs: Vec<(SectionAssignment, usize)>,
+}
+
+impl CoverageReport {
+    fn from(assignment_groups: &[AssignmentGroup]) -> Option<CoverageReport> {
+        // Ends sort before starts at the same position, as in
+        // `SectionIndex::max_coverage`
+        let mut events = assignment_groups
+            .iter()
+            .flat_map(|group| group.members.iter())
+            .filter(|a| !a.is_empty())
+            .flat_map(|a| [(a.lower, 1), (a.upper + 1, -1)])
+            .collect::<Vec<(usize, i64)>>();
+        events.sort();
+
+        let mut runs: Vec<(SectionAssignment, usize)> = vec![];
+        let mut coverage = 0;
+        let mut position = events.first()?.0;
+
+        for (section, change) in events {
+            if section > position {
+                let count = coverage as usize;
+                match runs.last_mut() {
+                    Some((run, run_count)) if *run_count == count => run.upper = section - 1,
+                    _ => runs.push((SectionAssignment::new(position, section - 1), count)),
+                }
+                position = section;
+            }
+            coverage += change;
+        }
+
+        Some(CoverageReport { runs })
+    }
+
+    fn overall_range(&self) -> SectionAssignment {
+        SectionAssignment::new(self.runs[0].0.lower, self.runs[self.runs.len() - 1].0.upper)
+    }
+
+    fn sections_covered(&self) -> usize {
+        self.runs
+            .iter()
+            .filter(|&&(_, count)| count > 0)
+            .map(|(run, _)| run.len())
+            .sum()
+    }
+
+    fn sections_shared(&self) -> usize {
+        self.runs
+            .iter()
+            .filter(|&&(_, count)| count > 1)
+            .map(|(run, _)| run.len())
+            .sum()
+    }
+
+    /// Section assignments handed out per section actually covered
+    fn redundancy_factor(&self) -> f64 {
+        let assigned = self
+            .runs
+            .iter()
+            .map(|(run, count)| run.len() * count)
+            .sum::<usize>();
+        assigned as f64 / self.sections_covered() as f64
+    }
+
+    fn runs(&self) -> &[(SectionAssignment, usize)] {
+        &self.runs
+    }
+
+    fn gaps(&self) -> Vec<SectionAssignment> {
+        self.runs()
+            .iter()
+            .filter(|&&(_, count)| count == 0)
+            .map(|&(run, _)| run)
+            .collect()
+    }
+
+    /// One row per elf in the style of the puzzle's diagrams, showing the
+    /// last digit of each assigned section and `.` elsewhere
+    fn strip_chart(&self, assignment_groups: &[AssignmentGroup]) -> String {
+        let range = self.overall_range();
+
+        assignment_groups
+            .iter()
+            .flat_map(|group| group.members.iter().copied())
+            .map(|assignment| {
+                let strip = (range.lower..=range.upper)
+                    .map(|section| {
+                        if assignment.contains_section(section) {
+                            char::from_digit((section % 10) as u32, 10).unwrap()
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect::<String>();
+                format!("{}  {}", strip, assignment)
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+impl Display for CoverageReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format_runs = |runs: Vec<String>| {
+            if runs.is_empty() {
+                String::from("none")
+            } else {
+                runs.join(" ")
+            }
         };
 
-        AssignmentPair { first, second }
+        writeln!(f, "Overall range: {}", self.overall_range())?;
+        writeln!(f, "Sections covered: {}", self.sections_covered())?;
+        writeln!(
+            f,
+            "Sections assigned to more than one elf: {}",
+            self.sections_shared()
+        )?;
+        writeln!(f, "Redundancy factor: {:.2}", self.redundancy_factor())?;
+        writeln!(
+            f,
+            "Gaps: {}",
+            format_runs(self.gaps().iter().map(|gap| gap.to_string()).collect())
+        )?;
+        write!(
+            f,
+            "Coverage: {}",
+            format_runs(
+                self.runs()
+                    .iter()
+                    .map(|(run, count)| format!("{}:{}", run, count))
+                    .collect()
+            )
+        )
     }
 }
 
-fn main() {
-    let assignment_pairs = include_str!("../inputs/day04.txt")
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reassignment {
+    Drop,
+    ShrinkTo(SectionAssignment),
+}
+
+/// The changes needed to leave every section with at most one elf.
+/// Assignments not listed are kept as they are
+#[derive(Debug)]
+struct ReassignmentPlan {
+    changes: Vec<(ElfId, SectionAssignment, Reassignment)>,
+}
+
+impl ReassignmentPlan {
+    /// Drops the fewest assignments possible, by greedily keeping the
+    /// assignment that ends first among those still compatible with the
+    /// ones already kept
+    fn fewest_assignments(assignments: &[(ElfId, SectionAssignment)]) -> ReassignmentPlan {
+        let mut order = assignments.to_vec();
+        order.sort_by_key(|&(elf, assignment)| (assignment.upper, assignment.lower, elf));
+
+        let mut last_kept: Option<usize> = None;
+        let mut changes = vec![];
+
+        for (elf, assignment) in order {
+            if last_kept.is_some_and(|upper| assignment.lower <= upper) {
+                changes.push((elf, assignment, Reassignment::Drop));
+            } else {
+                last_kept = Some(assignment.upper);
+            }
+        }
+
+        changes.sort_by_key(|&(elf, _, _)| elf);
+        ReassignmentPlan { changes }
+    }
+
+    /// Removes the fewest sections possible. Sweeping from the lowest
+    /// section, the uncovered remainder of the assignment reaching furthest
+    /// is kept each time, so every section that was covered stays covered
+    /// and only the overlaps are removed
+    fn fewest_sections(assignments: &[(ElfId, SectionAssignment)]) -> ReassignmentPlan {
+        let mut order = assignments.to_vec();
+        order.sort_by_key(|&(elf, assignment)| (assignment.lower, elf));
+
+        let mut pending = order.into_iter().peekable();
+        let mut available: BinaryHeap<(usize, Reverse<ElfId>, usize)> = BinaryHeap::new();
+        let mut changes = vec![];
+        let mut section = 0;
+
+        loop {
+            if available.is_empty() {
+                match pending.peek() {
+                    Some(&(_, assignment)) => section = section.max(assignment.lower),
+                    None => break,
+                }
+            }
+
+            while let Some(&(elf, assignment)) = pending.peek() {
+                if assignment.lower > section {
+                    break;
+                }
+                available.push((assignment.upper, Reverse(elf), assignment.lower));
+                pending.next();
+            }
+
+            let (upper, Reverse(elf), lower) = match available.pop() {
+                Some(next) => next,
+                None => unreachable!("Something always starts at or before the section"),
+            };
+            let assignment = SectionAssignment::new(lower, upper);
+
+            if upper < section {
+                changes.push((elf, assignment, Reassignment::Drop));
+                continue;
+            }
+
+            if lower < section {
+                let kept = SectionAssignment::new(section, upper);
+                changes.push((elf, assignment, Reassignment::ShrinkTo(kept)));
+            }
+            section = upper + 1;
+        }
+
+        changes.sort_by_key(|&(elf, _, _)| elf);
+        ReassignmentPlan { changes }
+    }
+
+    fn sections_removed(&self) -> usize {
+        self.changes
+            .iter()
+            .map(|(_, assignment, change)| match change {
+                Reassignment::Drop => assignment.len(),
+                Reassignment::ShrinkTo(kept) => assignment.len() - kept.len(),
+            })
+            .sum()
+    }
+}
+
+impl Display for ReassignmentPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (elf, assignment, change) in self.changes.iter() {
+            match change {
+                Reassignment::Drop => writeln!(f, "Drop elf {} ({})", elf, assignment)?,
+                Reassignment::ShrinkTo(kept) => {
+                    writeln!(f, "Shrink elf {} from {} to {}", elf, assignment, kept)?
+                }
+            }
+        }
+
+        write!(
+            f,
+            "Assignments changed: {}, sections removed: {}",
+            self.changes.len(),
+            self.sections_removed()
+        )
+    }
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse();
+
+    let assignment_groups = include_str!("../inputs/day04.txt")
         .lines()
         .map(|line| {
             line.split(",")
-                .filter_map(|range| range.parse::<SectionAssignment>().ok())
-                .collect::<AssignmentPair>()
+                .map(|range| range.parse::<SectionAssignment>())
+                .collect::<Result<AssignmentGroup, _>>()
         })
-        .collect::<Vec<AssignmentPair>>();
+        .collect::<Result<Vec<AssignmentGroup>, _>>()?;
 
-    let pairs_with_fully_contained_ranges = assignment_pairs
+    let groups_with_fully_contained_ranges = assignment_groups
         .iter()
-        .filter(|pair| {
-            pair.first.fully_contained_in(&pair.second)
-                || pair.second.fully_contained_in(&pair.first)
-        })
+        .filter(|group| group.has_contained_member())
         .count();
 
     println!(
-        "Number of pairs where one range is fully contained in the other: {}",
-        pairs_with_fully_contained_ranges
+        "Number of groups where one range is fully contained in another: {}",
+        groups_with_fully_contained_ranges
     );
 
-    let pairs_with_partially_contained_ranges = assignment_pairs
+    let groups_with_partially_contained_ranges = assignment_groups
         .iter()
-        .filter(|pair| {
-            pair.first.partially_contained_in(&pair.second)
-                || pair.second.partially_contained_in(&pair.first)
-        })
+        .filter(|group| !group.overlapping_members().is_empty())
         .count();
 
     println!(
-        "Number of pairs where one range is partially contained in the other: {}",
-        pairs_with_partially_contained_ranges
+        "Number of groups where one range is partially contained in another: {}",
+        groups_with_partially_contained_ranges
     );
+
+    if args.groups {
+        for (number, group) in assignment_groups.iter().enumerate() {
+            let overlapping = group
+                .overlapping_members()
+                .iter()
+                .map(|(i, j)| format!("{}&{}", i + 1, j + 1))
+                .collect::<Vec<String>>();
+
+            println!(
+                "Group {}: span {}, common {}, overlapping {}",
+                number + 1,
+                group.union_span(),
+                group.common_intersection(),
+                if overlapping.is_empty() {
+                    String::from("none")
+                } else {
+                    overlapping.join(" ")
+                }
+            );
+        }
+    }
+
+    let index = SectionIndex::from(&assignment_groups);
+    println!(
+        "Number of pairs of elves anywhere in the camp with overlapping assignments: {}",
+        index.count_overlapping_pairs()
+    );
+
+    if let Some((count, section)) = index.max_coverage() {
+        println!(
+            "Most elves assigned to a single section: {} (first at section {})",
+            count, section
+        );
+    }
+
+    if let Some(section) = args.elves_at {
+        let elves = index
+            .elves_at(section)
+            .iter()
+            .map(|elf| elf.to_string())
+            .collect::<Vec<String>>();
+        println!(
+            "Elves assigned to section {}: {}",
+            section,
+            elves.join(", ")
+        );
+    }
+
+    if args.list_overlaps {
+        for (first, second) in index.overlapping_pairs() {
+            println!("{} overlaps {}", first, second);
+        }
+    }
+
+    if args.coverage {
+        match CoverageReport::from(&assignment_groups) {
+            Some(report) => {
+                if args.chart {
+                    println!("{}", report.strip_chart(&assignment_groups));
+                }
+                println!("{}", report);
+            }
+            None => println!("No sections are assigned"),
+        }
+    }
+
+    if let Some(strategy) = args.resolve {
+        let assignments = camp_assignments(&assignment_groups);
+        let plan = match strategy {
+            ResolveStrategy::FewestAssignments => {
+                ReassignmentPlan::fewest_assignments(&assignments)
+            }
+            ResolveStrategy::FewestSections => ReassignmentPlan::fewest_sections(&assignments),
+        };
+        println!("{}", plan);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The empty assignment and every range within sections 0 to 10
+    fn small_samples() -> Vec<SectionAssignment> {
+        let mut samples = vec![SectionAssignment::empty()];
+        for lower in 0..=10 {
+            for upper in lower..=10 {
+                samples.push(SectionAssignment::new(lower, upper));
+            }
+        }
+        samples
+    }
+
+    fn input_samples() -> Vec<SectionAssignment> {
+        include_str!("../inputs/day04.txt")
+            .lines()
+            .flat_map(|line| line.split(','))
+            .map(|range| range.parse::<SectionAssignment>().unwrap())
+            .collect()
+    }
+
+    fn check_identities(a: &SectionAssignment) {
+        assert!(a.intersection(a) == *a, "{:?} ∩ itself is not itself", a);
+        assert!(a.union(a) == Some(*a), "{:?} ∪ itself is not itself", a);
+        assert!(a.contains(a), "{:?} does not contain itself", a);
+        assert!(a.difference(a).is_empty(), "{:?} \\ itself is not empty", a);
+        assert!(
+            a.is_empty() || a.len() == a.upper - a.lower + 1,
+            "{:?} has the wrong length",
+            a
+        );
+    }
+
+    fn check_pair(a: &SectionAssignment, b: &SectionAssignment) {
+        let intersection = a.intersection(b);
+
+        assert!(
+            intersection == b.intersection(a),
+            "∩ is not commutative for {:?} and {:?}",
+            a,
+            b
+        );
+        assert!(
+            a.overlaps(b) == b.overlaps(a) && a.overlaps(b) != intersection.is_empty(),
+            "overlaps is inconsistent for {:?} and {:?}",
+            a,
+            b
+        );
+        assert!(
+            a.contains(&intersection) && b.contains(&intersection),
+            "{:?} ∩ {:?} is not contained in both",
+            a,
+            b
+        );
+        assert!(
+            a.contains(b) == (intersection == *b),
+            "contains disagrees with ∩ for {:?} and {:?}",
+            a,
+            b
+        );
+
+        let adjacent =
+            !a.is_empty() && !b.is_empty() && (a.upper + 1 == b.lower || b.upper + 1 == a.lower);
+
+        match a.union(b) {
+            Some(union) => {
+                assert!(
+                    a.union(b) == b.union(a),
+                    "∪ is not commutative for {:?} and {:?}",
+                    a,
+                    b
+                );
+                assert!(
+                    union.len() + intersection.len() == a.len() + b.len(),
+                    "|{:?} ∪ {:?}| breaks inclusion-exclusion",
+                    a,
+                    b
+                );
+                assert!(
+                    union.contains(a) && union.contains(b),
+                    "{:?} ∪ {:?} does not contain both",
+                    a,
+                    b
+                );
+            }
+            None => assert!(
+                !a.is_empty() && !b.is_empty() && !a.overlaps(b) && !adjacent,
+                "{:?} ∪ {:?} should be a single range",
+                a,
+                b
+            ),
+        }
+
+        let difference = a.difference(b);
+        assert!(
+            difference.iter().map(|d| d.len()).sum::<usize>() + intersection.len() == a.len(),
+            "|{:?} \\ {:?}| does not account for every section",
+            a,
+            b
+        );
+        assert!(
+            difference
+                .iter()
+                .all(|d| !d.is_empty() && a.contains(d) && !d.overlaps(b)),
+            "{:?} \\ {:?} leaves the difference",
+            a,
+            b
+        );
+
+        // Ranges only change membership around their bounds
+        let sections = [a, b]
+            .into_iter()
+            .filter(|r| !r.is_empty())
+            .flat_map(|r| [r.lower.saturating_sub(1), r.lower, r.upper, r.upper + 1]);
+
+        for section in sections {
+            assert!(
+                intersection.contains_section(section)
+                    == (a.contains_section(section) && b.contains_section(section)),
+                "{:?} ∩ {:?} disagrees on section {}",
+                a,
+                b,
+                section
+            );
+            assert!(
+                difference.iter().any(|d| d.contains_section(section))
+                    == (a.contains_section(section) && !b.contains_section(section)),
+                "{:?} \\ {:?} disagrees on section {}",
+                a,
+                b,
+                section
+            );
+        }
+    }
+
+    #[test]
+    fn identities_hold() {
+        for a in small_samples().iter().chain(input_samples().iter()) {
+            check_identities(a);
+        }
+    }
+
+    #[test]
+    fn pairwise_laws_hold_for_small_ranges() {
+        let samples = small_samples();
+        for a in samples.iter() {
+            for b in samples.iter() {
+                check_pair(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn pairwise_laws_hold_for_input_assignments() {
+        // A fixed sample of the input, checked against itself and against
+        // ranges at the edges of the sections it uses
+        let inputs = input_samples().into_iter().step_by(10).collect::<Vec<_>>();
+        let mut samples = vec![
+            SectionAssignment::empty(),
+            SectionAssignment::new(0, 0),
+            SectionAssignment::new(1, 1),
+            SectionAssignment::new(99, 99),
+            SectionAssignment::new(100, 100),
+            SectionAssignment::new(0, 100),
+        ];
+        samples.extend(inputs.iter().copied());
+
+        for a in inputs.iter() {
+            for b in samples.iter() {
+                check_pair(a, b);
+                check_pair(b, a);
+            }
+        }
+    }
+
+    #[test]
+    fn intersection_is_associative() {
+        let samples = small_samples();
+        // Associativity is cubic, so only check it on the smallest samples
+        let small = samples.iter().filter(|a| a.upper <= 6).collect::<Vec<_>>();
+        for a in small.iter() {
+            for b in small.iter() {
+                for c in small.iter() {
+                    assert!(
+                        a.intersection(b).intersection(c) == a.intersection(&b.intersection(c)),
+                        "∩ is not associative for {:?}, {:?} and {:?}",
+                        a,
+                        b,
+                        c
+                    );
+                }
+            }
+        }
+    }
+
+    fn group(ranges: &[(usize, usize)]) -> AssignmentGroup {
+        ranges
+            .iter()
+            .map(|&(lower, upper)| SectionAssignment::new(lower, upper))
+            .collect()
+    }
+
+    #[test]
+    fn coverage_runs_match_the_assignments() {
+        let groups = [
+            group(&[(2, 4), (6, 8)]),
+            group(&[(2, 3), (4, 5)]),
+            group(&[(5, 7), (7, 9)]),
+            group(&[(12, 12), (12, 13)]),
+        ];
+        let report = CoverageReport::from(&groups).unwrap();
+
+        let expected = [
+            (2, 6, 2),
+            (7, 7, 3),
+            (8, 8, 2),
+            (9, 9, 1),
+            (10, 11, 0),
+            (12, 12, 2),
+            (13, 13, 1),
+        ]
+        .map(|(lower, upper, count)| (SectionAssignment::new(lower, upper), count));
+
+        assert_eq!(report.runs(), expected);
+        assert_eq!(report.overall_range(), SectionAssignment::new(2, 13));
+        assert_eq!(report.sections_covered(), 10);
+        assert_eq!(report.sections_shared(), 8);
+        assert_eq!(report.gaps(), vec![SectionAssignment::new(10, 11)]);
+    }
+
+    #[test]
+    fn coverage_of_a_huge_range_is_run_length() {
+        let report = CoverageReport::from(&[group(&[(1, 4_000_000_000), (5, 6)])]).unwrap();
+
+        assert_eq!(report.runs().len(), 3);
+        assert_eq!(report.sections_covered(), 4_000_000_000);
+        assert_eq!(report.sections_shared(), 2);
+    }
 }
@@ -1,17 +1,135 @@
-use std::str::FromStr;
+use anyhow::{anyhow, bail, Context};
+use clap::{Parser, Subcommand};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    fmt::{self, Display},
+    fs,
+    path::PathBuf,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
 
-#[derive(Debug)]
+#[derive(Debug, Parser)]
+struct Args {
+    /// Crane models to run over the instructions: `9000`, `9001`,
+    /// `limited-<capacity>`, optionally followed by `@<cost per lift>`
+    #[arg(long = "model", default_values = ["9000", "9001"])]
+    models: Vec<String>,
+
+    /// Read the drawing and instructions from this file instead of the
+    /// puzzle input
+    #[arg(long)]
+    input: Option<PathBuf>,
+
+    /// Reject unparsable instruction lines instead of skipping them
+    #[arg(long)]
+    strict: bool,
+
+    /// Show how every model's stacks differ from the first model's after
+    /// this many instructions
+    #[arg(long)]
+    compare_at: Option<usize>,
+
+    /// Find the first instruction after which the models' top crates differ
+    #[arg(long)]
+    first_divergence: bool,
+
+    /// Print the final stacks of every model as a drawing
+    #[arg(long)]
+    render: bool,
+
+    /// Redraw the first model's stacks in the terminal after every move
+    #[arg(long)]
+    animate: bool,
+
+    /// Delay between animation frames in milliseconds
+    #[arg(long, default_value_t = 100)]
+    frame_delay: u64,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Print a randomly generated drawing and instructions in the input format
+    Generate(GeneratorOptions),
+
+    /// Time the simulation of each model over a randomly generated input
+    Bench {
+        #[command(flatten)]
+        options: GeneratorOptions,
+
+        /// Also time the simple vector based stacks and check they agree
+        #[arg(long)]
+        baseline: bool,
+    },
+
+    /// Reconstruct the starting stacks for each model from a drawing of the
+    /// final stacks, running the instructions backwards. The drawing in the
+    /// input is ignored, and crates marked `[?]` are unknown
+    Unsolve { final_drawing: PathBuf },
+
+    /// Search for the shortest list of instructions that takes the drawing
+    /// in the input to a target arrangement, for each model
+    Plan {
+        /// Drawing of the target stacks
+        #[arg(long, required_unless_present = "top", conflicts_with = "top")]
+        target: Option<PathBuf>,
+
+        /// Crates that should end up on top of the stacks, read left to right
+        #[arg(long)]
+        top: Option<String>,
+
+        /// Longest list of instructions to consider
+        #[arg(long, default_value_t = 8)]
+        max_depth: usize,
+
+        /// Give up after discovering this many distinct arrangements
+        #[arg(long, default_value_t = 200_000)]
+        max_states: usize,
+    },
+}
+
+#[derive(Debug, clap::Args)]
+struct GeneratorOptions {
+    #[arg(long, default_value_t = 9)]
+    stacks: usize,
+
+    #[arg(long, default_value_t = 100_000)]
+    crates: usize,
+
+    #[arg(long, default_value_t = 1_000_000)]
+    instructions: usize,
+
+    /// Most crates moved by a single instruction
+    #[arg(long, default_value_t = 1_000)]
+    max_move: usize,
+
+    #[arg(long, default_value_t = 2022)]
+    seed: u64,
+}
+
+#[derive(Debug, Clone)]
 struct Instruction {
     start_stack: usize,
     target_stack: usize,
     crates_to_move: usize,
+    /// Line of the input file the instruction was read from
+    line: Option<usize>,
 }
 
-impl FromStr for Instruction {
-    type Err = anyhow::Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut it = s.split_whitespace().take(3);
+impl Instruction {
+    /// Reads the first three numbers on a line, ignoring the keywords and
+    /// anything that follows them
+    fn parse_lenient(s: &str) -> Result<Self, anyhow::Error> {
+        let numbers = s
+            .replace("move ", "")
+            .replace("from ", "")
+            .replace("to ", "");
+        let mut it = numbers.split_whitespace().take(3);
         let crates_to_move_str = it.next();
         let start_stack_str = it.next();
         let target_stack_str = it.next();
@@ -21,120 +139,927 @@ impl FromStr for Instruction {
                 (Some(crates_to_move), Some(start_stack), Some(target_stack)) => {
                     (crates_to_move, start_stack, target_stack)
                 }
-                _ => unreachable!("Invalid input"),
+                _ => bail!("Expected an amount, a source and a target stack"),
             };
 
-        let crates_to_move = crates_to_move.parse::<usize>()?;
-        let start_stack = start_stack.parse::<usize>()?;
-        let target_stack = target_stack.parse::<usize>()?;
+        Ok(Instruction {
+            start_stack: start_stack.parse::<usize>()?,
+            target_stack: target_stack.parse::<usize>()?,
+            crates_to_move: crates_to_move.parse::<usize>()?,
+            line: None,
+        })
+    }
+}
+
+impl FromStr for Instruction {
+    type Err = anyhow::Error;
+
+    /// Parses exactly `move N from A to B`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = s.split_whitespace().collect::<Vec<&str>>();
+
+        let (crates_to_move, start_stack, target_stack) = match tokens.as_slice() {
+            ["move", crates_to_move, "from", start_stack, "to", target_stack] => {
+                (crates_to_move, start_stack, target_stack)
+            }
+            _ => bail!("Expected 'move N from A to B'"),
+        };
 
         Ok(Instruction {
-            start_stack,
-            target_stack,
-            crates_to_move,
+            start_stack: start_stack.parse::<usize>()?,
+            target_stack: target_stack.parse::<usize>()?,
+            crates_to_move: crates_to_move.parse::<usize>()?,
+            line: None,
         })
     }
 }
 
-#[derive(Debug, Clone)]
+impl Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "move {} from {} to {}",
+            self.crates_to_move, self.start_stack, self.target_stack
+        )
+    }
+}
+
+/// Why an instruction could not be carried out. Instructions are numbered
+/// from 1 in the order they are performed, and `line` is where the
+/// instruction was read from, if it came from a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CraneError {
+    NoSuchStack {
+        instruction: usize,
+        line: Option<usize>,
+        stack: usize,
+    },
+    Shortfall {
+        instruction: usize,
+        line: Option<usize>,
+        stack: usize,
+        requested: usize,
+        available: usize,
+    },
+}
+
+impl Display for CraneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CraneError::NoSuchStack {
+                instruction,
+                line,
+                stack,
+            } => {
+                write!(
+                    f,
+                    "Instruction {}{}: there is no stack {}",
+                    instruction,
+                    source_line(*line),
+                    stack
+                )
+            }
+            CraneError::Shortfall {
+                instruction,
+                line,
+                stack,
+                requested,
+                available,
+            } => write!(
+                f,
+                "Instruction {}{}: stack {} holds {} crates but {} were requested, {} short",
+                instruction,
+                source_line(*line),
+                stack,
+                available,
+                requested,
+                requested - available
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CraneError {}
+
+fn source_line(line: Option<usize>) -> String {
+    match line {
+        Some(line) => format!(" (line {})", line),
+        None => String::new(),
+    }
+}
+
+/// The label written inside a crate's brackets in the drawing
+type Crate = String;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 struct Stack {
-    crates: Vec<char>,
+    crates: Vec<Crate>,
 }
 
 impl Stack {
-    fn push(&mut self, item: char) {
+    fn push(&mut self, item: Crate) {
         self.crates.push(item)
     }
 
-    fn pop_multiple(&mut self, amount: usize) -> Vec<char> {
+    fn pop_multiple_in_order(&mut self, amount: usize) -> Vec<Crate> {
         let new_len = self.crates.len() - amount;
 
-        self.crates.drain(new_len..).rev().collect::<Vec<char>>()
+        self.crates.drain(new_len..).collect::<Vec<Crate>>()
     }
+}
 
-    fn pop_multiple_in_order(&mut self, amount: usize) -> Vec<char> {
-        let new_len = self.crates.len() - amount;
+/// A crane picks crates up from the top of the source stack in lifts of at
+/// most `capacity` crates, setting each lift down on the target stack in the
+/// order it was picked up
+trait CraneModel {
+    fn name(&self) -> String;
+
+    fn capacity(&self) -> usize;
+
+    fn lifts(&self, amount: usize) -> usize {
+        amount.div_ceil(self.capacity())
+    }
+
+    fn cost(&self, amount: usize) -> usize {
+        self.lifts(amount)
+    }
+}
+
+/// Moves one crate at a time
+#[derive(Debug)]
+struct CrateMover9000;
+
+impl CraneModel for CrateMover9000 {
+    fn name(&self) -> String {
+        String::from("CrateMover 9000")
+    }
+
+    fn capacity(&self) -> usize {
+        1
+    }
+}
 
-        self.crates.drain(new_len..).collect::<Vec<char>>()
+/// Moves any number of crates at once
+#[derive(Debug)]
+struct CrateMover9001;
+
+impl CraneModel for CrateMover9001 {
+    fn name(&self) -> String {
+        String::from("CrateMover 9001")
+    }
+
+    fn capacity(&self) -> usize {
+        usize::MAX
     }
 }
 
 #[derive(Debug)]
+struct LimitedCrateMover {
+    capacity: usize,
+}
+
+impl CraneModel for LimitedCrateMover {
+    fn name(&self) -> String {
+        format!("CrateMover with capacity {}", self.capacity)
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// Charges a fixed cost for every lift of the wrapped model
+struct Metered {
+    model: Box<dyn CraneModel>,
+    cost_per_lift: usize,
+}
+
+impl CraneModel for Metered {
+    fn name(&self) -> String {
+        format!("{} at {} per lift", self.model.name(), self.cost_per_lift)
+    }
+
+    fn capacity(&self) -> usize {
+        self.model.capacity()
+    }
+
+    fn cost(&self, amount: usize) -> usize {
+        self.model.cost(amount) * self.cost_per_lift
+    }
+}
+
+fn parse_model(s: &str) -> Result<Box<dyn CraneModel>, anyhow::Error> {
+    let (model, cost_per_lift) = match s.split_once("@") {
+        Some((model, cost)) => (model, Some(cost.parse::<usize>()?)),
+        None => (s, None),
+    };
+
+    let model: Box<dyn CraneModel> = match model {
+        "9000" => Box::new(CrateMover9000),
+        "9001" => Box::new(CrateMover9001),
+        _ => match model.strip_prefix("limited-") {
+            Some(capacity) => {
+                let capacity = capacity.parse::<usize>()?;
+                if capacity == 0 {
+                    bail!("A crane must be able to lift at least one crate");
+                }
+                Box::new(LimitedCrateMover { capacity })
+            }
+            None => return Err(anyhow!("Unknown crane model {:?}", s)),
+        },
+    };
+
+    Ok(match cost_per_lift {
+        Some(cost_per_lift) => Box::new(Metered {
+            model,
+            cost_per_lift,
+        }),
+        None => model,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 struct Crane {
+    /// The number under each stack in the drawing, which instructions use to
+    /// refer to it
+    labels: Vec<usize>,
     stacks: Vec<Stack>,
 }
 
 impl Crane {
-    fn from(stacks: Vec<Stack>) -> Crane {
-        Crane { stacks }
+    fn from(labels: Vec<usize>, stacks: Vec<Stack>) -> Crane {
+        Crane { labels, stacks }
     }
 
-    fn push_at(&mut self, index: usize, item: char) {
+    fn push_at(&mut self, index: usize, item: Crate) {
         self.stacks[index].push(item)
     }
 
-    fn pop_multiple_at(&mut self, index: usize, amount: usize) -> Vec<char> {
-        self.stacks[index].pop_multiple(amount)
+    fn pop_multiple_at_in_order(&mut self, index: usize, amount: usize) -> Vec<Crate> {
+        self.stacks[index].pop_multiple_in_order(amount)
     }
 
-    fn pop_multiple_at_in_order(&mut self, index: usize, amount: usize) -> Vec<char> {
-        self.stacks[index].pop_multiple_in_order(amount)
+    /// Maps a stack label from an instruction to its index
+    fn stack_index(
+        &self,
+        number: usize,
+        line: Option<usize>,
+        stack: usize,
+    ) -> Result<usize, CraneError> {
+        match self.labels.iter().position(|&label| label == stack) {
+            Some(index) => Ok(index),
+            None => Err(CraneError::NoSuchStack {
+                instruction: number,
+                line,
+                stack,
+            }),
+        }
     }
 
-    fn perform_9000(&mut self, instruction: &Instruction) {
-        let crates_to_move =
-            self.pop_multiple_at(instruction.start_stack - 1, instruction.crates_to_move);
+    /// Carries out instruction `number`, leaving the crane untouched if the
+    /// instruction is invalid
+    fn perform(
+        &mut self,
+        number: usize,
+        instruction: &Instruction,
+        model: &dyn CraneModel,
+    ) -> Result<AppliedMove, CraneError> {
+        let start = self.stack_index(number, instruction.line, instruction.start_stack)?;
+        let target = self.stack_index(number, instruction.line, instruction.target_stack)?;
 
-        for c in crates_to_move.iter() {
-            self.push_at(instruction.target_stack - 1, *c)
+        let available = self.stacks[start].crates.len();
+        if available < instruction.crates_to_move {
+            return Err(CraneError::Shortfall {
+                instruction: number,
+                line: instruction.line,
+                stack: instruction.start_stack,
+                requested: instruction.crates_to_move,
+                available,
+            });
         }
+
+        let crates_to_move = self.pop_multiple_at_in_order(start, instruction.crates_to_move);
+        let placed = crates_to_move
+            .rchunks(model.capacity())
+            .flatten()
+            .cloned()
+            .collect::<Vec<Crate>>();
+
+        for c in placed.iter() {
+            self.push_at(target, c.clone());
+        }
+
+        Ok(AppliedMove {
+            start,
+            target,
+            taken: crates_to_move,
+            placed,
+        })
+    }
+
+    /// Reverses instruction `number`, as carried out by `model`, on a crane
+    /// that has already had it applied
+    fn unperform(
+        &mut self,
+        number: usize,
+        instruction: &Instruction,
+        model: &dyn CraneModel,
+    ) -> Result<(), CraneError> {
+        let start = self.stack_index(number, instruction.line, instruction.start_stack)?;
+        let target = self.stack_index(number, instruction.line, instruction.target_stack)?;
+
+        let available = self.stacks[target].crates.len();
+        if available < instruction.crates_to_move {
+            return Err(CraneError::Shortfall {
+                instruction: number,
+                line: instruction.line,
+                stack: instruction.target_stack,
+                requested: instruction.crates_to_move,
+                available,
+            });
+        }
+
+        // The lifts were taken from the top of the start stack first, so the
+        // first lift placed is the last one to put back
+        let placed = self.pop_multiple_at_in_order(target, instruction.crates_to_move);
+        for lift in placed.chunks(model.capacity()).rev() {
+            for c in lift.iter() {
+                self.push_at(start, c.clone());
+            }
+        }
+
+        Ok(())
     }
 
-    fn perform_9001(&mut self, instruction: &Instruction) {
-        let crates_to_move =
-            self.pop_multiple_at_in_order(instruction.start_stack - 1, instruction.crates_to_move);
+    fn undo(&mut self, applied: &AppliedMove) {
+        self.pop_multiple_at_in_order(applied.target, applied.placed.len());
+        for c in applied.taken.iter() {
+            self.push_at(applied.start, c.clone());
+        }
+    }
 
-        for c in crates_to_move.iter() {
-            self.push_at(instruction.target_stack - 1, *c);
+    fn redo(&mut self, applied: &AppliedMove) {
+        self.pop_multiple_at_in_order(applied.start, applied.taken.len());
+        for c in applied.placed.iter() {
+            self.push_at(applied.target, c.clone());
         }
     }
 
+    /// The stacks that differ between the two cranes
+    fn diff(&self, other: &Crane) -> Vec<StackDiff> {
+        let empty = Stack { crates: vec![] };
+
+        (0..self.stacks.len().max(other.stacks.len()))
+            .filter_map(|index| {
+                let before = self.stacks.get(index).unwrap_or(&empty);
+                let after = other.stacks.get(index).unwrap_or(&empty);
+
+                let common = before
+                    .crates
+                    .iter()
+                    .zip(after.crates.iter())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+
+                if common == before.crates.len() && common == after.crates.len() {
+                    return None;
+                }
+
+                Some(StackDiff {
+                    stack: self.labels.get(index).copied().unwrap_or(index + 1),
+                    common,
+                    removed: before.crates[common..].to_vec(),
+                    added: after.crates[common..].to_vec(),
+                })
+            })
+            .collect()
+    }
+
     fn display_top_crates(&self) -> String {
         self.stacks
             .iter()
             .filter_map(|stack| stack.crates.last())
-            .into_iter()
+            .map(|c| c.as_str())
             .collect()
     }
 }
 
+/// What one instruction did to the crane, enough to undo or redo it.
+/// `taken` is in the order the crates were stacked on the start stack and
+/// `placed` in the order they ended up on the target stack
+#[derive(Debug, Clone)]
+struct AppliedMove {
+    start: usize,
+    target: usize,
+    taken: Vec<Crate>,
+    placed: Vec<Crate>,
+}
+
+/// How a stack differs between two snapshots, above the `common` crates at
+/// the bottom they share
+#[derive(Debug)]
+struct StackDiff {
+    stack: usize,
+    common: usize,
+    removed: Vec<Crate>,
+    added: Vec<Crate>,
+}
+
+impl Display for StackDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let crates = |crates: &[Crate]| {
+            if crates.is_empty() {
+                String::from("nothing")
+            } else {
+                crates.concat()
+            }
+        };
+
+        write!(
+            f,
+            "Stack {}: above {} shared crates, {} became {}",
+            self.stack,
+            self.common,
+            crates(&self.removed),
+            crates(&self.added)
+        )
+    }
+}
+
+/// Replays instructions on a crane while recording what each one did, so
+/// the crane can be stepped backwards and forwards through them
+struct CraneHistory<'a> {
+    crane: Crane,
+    model: &'a dyn CraneModel,
+    instructions: &'a [Instruction],
+    applied: Vec<AppliedMove>,
+    /// Number of instructions currently applied
+    position: usize,
+}
+
+impl<'a> CraneHistory<'a> {
+    fn new(
+        crane: Crane,
+        model: &'a dyn CraneModel,
+        instructions: &'a [Instruction],
+    ) -> CraneHistory<'a> {
+        CraneHistory {
+            crane,
+            model,
+            instructions,
+            applied: vec![],
+            position: 0,
+        }
+    }
+
+    /// Applies the next instruction, returning false if there are none left
+    fn step_forward(&mut self) -> Result<bool, CraneError> {
+        if self.position < self.applied.len() {
+            self.crane.redo(&self.applied[self.position]);
+        } else if let Some(instruction) = self.instructions.get(self.position) {
+            let applied = self
+                .crane
+                .perform(self.position + 1, instruction, self.model)?;
+            self.applied.push(applied);
+        } else {
+            return Ok(false);
+        }
+
+        self.position += 1;
+        Ok(true)
+    }
+
+    /// Undoes the last applied instruction, returning false if there is none
+    fn step_back(&mut self) -> bool {
+        if self.position == 0 {
+            return false;
+        }
+
+        self.position -= 1;
+        self.crane.undo(&self.applied[self.position]);
+        true
+    }
+
+    /// Moves to the state after instruction `number`, or after the last
+    /// instruction if there are fewer
+    fn jump_to(&mut self, number: usize) -> Result<&Crane, CraneError> {
+        while self.position > number && self.step_back() {}
+        while self.position < number && self.step_forward()? {}
+
+        Ok(&self.crane)
+    }
+
+    fn snapshot(&self) -> Crane {
+        self.crane.clone()
+    }
+}
+
+#[derive(Debug)]
+struct SimulationResult {
+    model: String,
+    top_crates: String,
+    lifts: usize,
+    cost: usize,
+}
+
+/// Runs every model over the same instructions, each on its own rope built
+/// from the starting crane
+fn simulate(
+    crane: &Crane,
+    instructions: &[Instruction],
+    models: &[Box<dyn CraneModel>],
+) -> Result<Vec<SimulationResult>, CraneError> {
+    models
+        .iter()
+        .map(|model| {
+            let mut rope = CrateRope::from(crane);
+            let mut lifts = 0;
+            let mut cost = 0;
+
+            for (number, instruction) in instructions.iter().enumerate() {
+                rope.perform(number + 1, instruction, model.as_ref())?;
+                lifts += model.lifts(instruction.crates_to_move);
+                cost += model.cost(instruction.crates_to_move);
+            }
+
+            Ok(SimulationResult {
+                model: model.name(),
+                top_crates: rope.display_top_crates(),
+                lifts,
+                cost,
+            })
+        })
+        .collect()
+}
+
+/// Index of the empty tree in `CrateRope::nodes`
+const NIL: usize = 0;
+
+#[derive(Debug, Clone, Copy)]
+struct RopeNode {
+    crate_id: u32,
+    priority: u64,
+    left: usize,
+    right: usize,
+    size: usize,
+    /// The subtree's order is flipped, but the flip has not yet been pushed
+    /// down to its children
+    reversed: bool,
+}
+
+/// Stacks kept as implicit treaps, one per stack, sharing a single node
+/// arena. Moving a block of crates is a split and a merge, and reversing one
+/// is a lazy flag, so every instruction takes O(log n) expected time per
+/// lift without allocating
+#[derive(Debug)]
+struct CrateRope {
+    nodes: Vec<RopeNode>,
+    roots: Vec<usize>,
+    labels: HashMap<usize, usize>,
+    names: Vec<Crate>,
+    rng: Rng,
+}
+
+impl CrateRope {
+    fn from(crane: &Crane) -> CrateRope {
+        let mut rope = CrateRope {
+            nodes: vec![RopeNode {
+                crate_id: 0,
+                priority: 0,
+                left: NIL,
+                right: NIL,
+                size: 0,
+                reversed: false,
+            }],
+            roots: vec![NIL; crane.stacks.len()],
+            labels: crane
+                .labels
+                .iter()
+                .enumerate()
+                .map(|(index, &label)| (label, index))
+                .collect(),
+            names: vec![],
+            rng: Rng::new(0x5eed),
+        };
+
+        let mut ids: HashMap<&Crate, u32> = HashMap::new();
+
+        for (index, stack) in crane.stacks.iter().enumerate() {
+            for c in stack.crates.iter() {
+                let id = *ids.entry(c).or_insert_with(|| {
+                    rope.names.push(c.clone());
+                    (rope.names.len() - 1) as u32
+                });
+
+                let node = rope.new_node(id);
+                rope.roots[index] = rope.merge(rope.roots[index], node);
+            }
+        }
+
+        rope
+    }
+
+    fn new_node(&mut self, crate_id: u32) -> usize {
+        self.nodes.push(RopeNode {
+            crate_id,
+            priority: self.rng.next(),
+            left: NIL,
+            right: NIL,
+            size: 1,
+            reversed: false,
+        });
+        self.nodes.len() - 1
+    }
+
+    fn update(&mut self, node: usize) {
+        let (left, right) = (self.nodes[node].left, self.nodes[node].right);
+        self.nodes[node].size = self.nodes[left].size + self.nodes[right].size + 1;
+    }
+
+    fn push_down(&mut self, node: usize) {
+        if node == NIL || !self.nodes[node].reversed {
+            return;
+        }
+
+        let RopeNode { left, right, .. } = self.nodes[node];
+        self.nodes[node].left = right;
+        self.nodes[node].right = left;
+        self.nodes[node].reversed = false;
+        self.nodes[left].reversed ^= left != NIL;
+        self.nodes[right].reversed ^= right != NIL;
+    }
+
+    /// Splits a tree into its first `count` crates and the rest
+    fn split(&mut self, node: usize, count: usize) -> (usize, usize) {
+        if node == NIL {
+            return (NIL, NIL);
+        }
+
+        self.push_down(node);
+        let left_size = self.nodes[self.nodes[node].left].size;
+
+        if count <= left_size {
+            let (first, rest) = self.split(self.nodes[node].left, count);
+            self.nodes[node].left = rest;
+            self.update(node);
+            (first, node)
+        } else {
+            let (first, rest) = self.split(self.nodes[node].right, count - left_size - 1);
+            self.nodes[node].right = first;
+            self.update(node);
+            (node, rest)
+        }
+    }
+
+    fn merge(&mut self, first: usize, second: usize) -> usize {
+        if first == NIL {
+            return second;
+        }
+        if second == NIL {
+            return first;
+        }
+
+        if self.nodes[first].priority > self.nodes[second].priority {
+            self.push_down(first);
+            let merged = self.merge(self.nodes[first].right, second);
+            self.nodes[first].right = merged;
+            self.update(first);
+            first
+        } else {
+            self.push_down(second);
+            let merged = self.merge(first, self.nodes[second].left);
+            self.nodes[second].left = merged;
+            self.update(second);
+            second
+        }
+    }
+
+    fn stack_index(
+        &self,
+        number: usize,
+        line: Option<usize>,
+        stack: usize,
+    ) -> Result<usize, CraneError> {
+        match self.labels.get(&stack) {
+            Some(&index) => Ok(index),
+            None => Err(CraneError::NoSuchStack {
+                instruction: number,
+                line,
+                stack,
+            }),
+        }
+    }
+
+    fn perform(
+        &mut self,
+        number: usize,
+        instruction: &Instruction,
+        model: &dyn CraneModel,
+    ) -> Result<(), CraneError> {
+        let start = self.stack_index(number, instruction.line, instruction.start_stack)?;
+        let target = self.stack_index(number, instruction.line, instruction.target_stack)?;
+        let amount = instruction.crates_to_move;
+
+        let available = self.nodes[self.roots[start]].size;
+        if available < amount {
+            return Err(CraneError::Shortfall {
+                instruction: number,
+                line: instruction.line,
+                stack: instruction.start_stack,
+                requested: amount,
+                available,
+            });
+        }
+
+        let (rest, mut moving) = self.split(self.roots[start], available - amount);
+        self.roots[start] = rest;
+
+        // Lifting one crate at a time reverses the whole block, which is
+        // cheaper to do in one go
+        let capacity = model.capacity();
+        if capacity == 1 && moving != NIL {
+            self.nodes[moving].reversed ^= true;
+            self.roots[target] = self.merge(self.roots[target], moving);
+            return Ok(());
+        }
+
+        while moving != NIL {
+            let size = self.nodes[moving].size;
+            let (below, lift) = self.split(moving, size - size.min(capacity));
+            self.roots[target] = self.merge(self.roots[target], lift);
+            moving = below;
+        }
+
+        Ok(())
+    }
+
+    fn top(&mut self, index: usize) -> Option<&Crate> {
+        let mut node = self.roots[index];
+        if node == NIL {
+            return None;
+        }
+
+        loop {
+            self.push_down(node);
+            match self.nodes[node].right {
+                NIL => return Some(&self.names[self.nodes[node].crate_id as usize]),
+                right => node = right,
+            }
+        }
+    }
+
+    fn display_top_crates(&mut self) -> String {
+        (0..self.roots.len())
+            .filter_map(|index| self.top(index).cloned())
+            .collect()
+    }
+}
+
+/// A xorshift generator, good enough for treap priorities and test inputs
+#[derive(Debug, Clone)]
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng { state: seed.max(1) }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+/// Builds a random crane and a list of instructions that is valid for every
+/// model, since stack heights do not depend on how crates are lifted
+fn generate(options: &GeneratorOptions) -> Result<(Crane, Vec<Instruction>), anyhow::Error> {
+    if options.stacks < 2 {
+        bail!("At least two stacks are needed to move crates");
+    }
+    if options.crates == 0 && options.instructions > 0 {
+        bail!("Instructions need crates to move");
+    }
+
+    let mut rng = Rng::new(options.seed);
+    let mut stacks = vec![Stack { crates: vec![] }; options.stacks];
+
+    for _ in 0..options.crates {
+        let name = char::from(b'A' + rng.below(26) as u8).to_string();
+        stacks[rng.below(options.stacks)].push(name);
+    }
+
+    let mut heights = stacks
+        .iter()
+        .map(|stack| stack.crates.len())
+        .collect::<Vec<usize>>();
+    let mut instructions = Vec::with_capacity(options.instructions);
+
+    while instructions.len() < options.instructions {
+        let start = rng.below(options.stacks);
+        if heights[start] == 0 {
+            continue;
+        }
+
+        let target = (start + 1 + rng.below(options.stacks - 1)) % options.stacks;
+        let amount = 1 + rng.below(heights[start].min(options.max_move.max(1)));
+
+        heights[start] -= amount;
+        heights[target] += amount;
+        instructions.push(Instruction {
+            start_stack: start + 1,
+            target_stack: target + 1,
+            crates_to_move: amount,
+            line: None,
+        });
+    }
+
+    Ok((
+        Crane::from((1..=options.stacks).collect(), stacks),
+        instructions,
+    ))
+}
+
 impl FromStr for Crane {
     type Err = anyhow::Error;
 
+    /// Stacks are located by their labels in the last line. Every crate
+    /// must line up with exactly one label and sit on top of another crate
+    /// or the floor, but the columns may be any width and lines may have
+    /// ragged trailing whitespace
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut it = s.lines().rev();
+        let lines = s.trim_end().lines().collect::<Vec<&str>>();
 
-        let stack_indices = match it.next() {
-            Some(line) => line
-                .split("")
-                .enumerate()
-                .filter_map(|(count, item)| match item.parse::<usize>() {
-                    Ok(_) => Some(count - 1),
-                    _ => None,
-                })
-                .collect::<Vec<usize>>(),
-            None => unreachable!("Shouldn't happen"),
+        let (footer, rows) = match lines.split_last() {
+            Some(split) => split,
+            None => bail!("The drawing is empty"),
         };
 
-        let mut crane = Crane::from(vec![Stack { crates: vec![] }; stack_indices.len()]);
+        let footer = tokens(footer);
+        let mut labels = vec![];
+
+        for (_, _, token) in footer.iter() {
+            let label = match token.parse::<usize>() {
+                Ok(label) => label,
+                Err(_) => bail!("Stack label {:?} is not a number", token),
+            };
+
+            if labels.contains(&label) {
+                bail!("Stack label {} appears more than once", label);
+            }
+            labels.push(label);
+        }
+
+        let mut crane = Crane::from(labels, vec![Stack { crates: vec![] }; footer.len()]);
 
-        while let Some(line) = it.next() {
-            for (index, stack_index) in stack_indices.iter().enumerate() {
-                let item = line.chars().nth(*stack_index);
-                match item {
-                    Some(item) if item.is_alphabetic() => crane.push_at(index, item),
-                    _ => continue,
+        for (level, row) in rows.iter().rev().enumerate() {
+            let line = rows.len() - level;
+
+            for (start, end, token) in tokens(row) {
+                let name = match token
+                    .strip_prefix('[')
+                    .and_then(|token| token.strip_suffix(']'))
+                {
+                    Some(name) if !name.is_empty() && !name.contains(['[', ']']) => name,
+                    _ => bail!("Line {}: {:?} is not a crate", line, token),
+                };
+
+                let columns = footer
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (label_start, label_end, _))| {
+                        start < *label_end && *label_start < end
+                    })
+                    .map(|(index, _)| index)
+                    .collect::<Vec<usize>>();
+
+                let index = match columns[..] {
+                    [index] => index,
+                    _ => bail!(
+                        "Line {}: crate {} at column {} does not line up with exactly one stack",
+                        line,
+                        token,
+                        start + 1
+                    ),
+                };
+
+                if crane.stacks[index].crates.len() != level {
+                    bail!(
+                        "Line {}: crate {} on stack {} has nothing beneath it",
+                        line,
+                        token,
+                        crane.labels[index]
+                    );
                 }
+
+                crane.push_at(index, String::from(name));
             }
         }
 
@@ -142,41 +1067,763 @@ impl FromStr for Crane {
     }
 }
 
-fn main() -> Result<(), anyhow::Error> {
-    let (drawing_str, instructions_str) = include_str!("../inputs/day05.txt")
-        .split_once("\n\n")
-        .unwrap();
-
-    let mut crane_9000 = drawing_str.parse::<Crane>()?;
-    let mut crane_9001 = drawing_str.parse::<Crane>()?;
-
-    let instructions = instructions_str
-        .lines()
-        .filter_map(|x| {
-            x.replace("move ", "")
-                .replace("from ", "")
-                .replace("to ", "")
-                .parse::<Instruction>()
-                .ok()
-        })
-        .collect::<Vec<Instruction>>();
+/// Splits a line into its whitespace separated tokens, along with the
+/// character columns each one starts at and ends before
+fn tokens(line: &str) -> Vec<(usize, usize, String)> {
+    let mut tokens = vec![];
+    let mut current: Option<(usize, String)> = None;
 
-    for instruction in instructions {
-        crane_9000.perform_9000(&instruction);
-        crane_9001.perform_9001(&instruction);
+    for (column, c) in line.chars().chain([' ']).enumerate() {
+        match (c.is_whitespace(), current.take()) {
+            (true, Some((start, token))) => tokens.push((start, column, token)),
+            (true, None) => {}
+            (false, Some((start, mut token))) => {
+                token.push(c);
+                current = Some((start, token));
+            }
+            (false, None) => current = Some((column, String::from(c))),
+        }
     }
 
-    let top_crates = crane_9000.display_top_crates();
-    let top_crates_9001 = crane_9001.display_top_crates();
+    tokens
+}
 
-    println!(
-        "Crates on top of each stack when using the 9000: {}",
-        top_crates
-    );
-    println!(
-        "Crates on top of each stack when using the 9001: {}",
-        top_crates_9001
+/// Marks a crate whose label is not known in a drawing
+const UNKNOWN_CRATE: &str = "?";
+
+/// Runs the instructions backwards from the final stacks to find the stacks
+/// they must have started from
+fn unsolve(
+    final_crane: &Crane,
+    instructions: &[Instruction],
+    model: &dyn CraneModel,
+) -> Result<Crane, CraneError> {
+    let mut crane = final_crane.clone();
+
+    for (number, instruction) in instructions.iter().enumerate().rev() {
+        crane.unperform(number + 1, instruction, model)?;
+    }
+
+    Ok(crane)
+}
+
+/// Describes the starting crates whose label was unknown, along with where
+/// each of them ends up. Running the instructions backwards is deterministic
+/// and crates sharing a label are interchangeable, so every other crate is
+/// pinned down
+fn unclear_origins(
+    final_crane: &Crane,
+    instructions: &[Instruction],
+    model: &dyn CraneModel,
+) -> Result<Vec<String>, CraneError> {
+    // Tag each unknown crate with where it ends up and follow the tags back
+    let tagged = Crane::from(
+        final_crane.labels.clone(),
+        final_crane
+            .labels
+            .iter()
+            .zip(final_crane.stacks.iter())
+            .map(|(label, stack)| Stack {
+                crates: stack
+                    .crates
+                    .iter()
+                    .enumerate()
+                    .map(|(level, c)| match c.as_str() {
+                        UNKNOWN_CRATE => format!("stack {}, crate {}", label, level + 1),
+                        _ => String::new(),
+                    })
+                    .collect(),
+            })
+            .collect(),
     );
+    let start = unsolve(&tagged, instructions, model)?;
+
+    let mut unclear = vec![];
+    for (label, stack) in start.labels.iter().zip(start.stacks.iter()) {
+        for (level, end) in stack.crates.iter().enumerate() {
+            if !end.is_empty() {
+                unclear.push(format!(
+                    "Stack {}, crate {} from the bottom is unconstrained (it ends up as {} from the bottom)",
+                    label,
+                    level + 1,
+                    end
+                ));
+            }
+        }
+    }
+
+    Ok(unclear)
+}
+
+#[derive(Debug)]
+enum PlanTarget {
+    Drawing(Crane),
+    TopCrates(String),
+}
+
+impl PlanTarget {
+    fn is_reached(&self, crane: &Crane) -> bool {
+        match self {
+            PlanTarget::Drawing(target) => crane == target,
+            PlanTarget::TopCrates(top_crates) => crane.display_top_crates() == *top_crates,
+        }
+    }
+
+    /// A lower bound on the instructions still needed. Every instruction
+    /// touches at most two stacks, so half the stacks still out of place
+    /// (rounded up) is never an overestimate
+    fn estimate(&self, crane: &Crane) -> usize {
+        match self {
+            PlanTarget::Drawing(target) => crane
+                .stacks
+                .iter()
+                .zip(target.stacks.iter())
+                .filter(|(stack, target)| stack != target)
+                .count()
+                .div_ceil(2),
+            PlanTarget::TopCrates(_) => usize::from(!self.is_reached(crane)),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum PlanOutcome {
+    Found(Vec<Instruction>),
+    /// No list of at most the maximum depth reaches the target
+    Unreachable,
+    StateLimitReached,
+}
+
+/// A* search over arrangements of the crane, with one step per instruction
+fn plan(
+    start: &Crane,
+    target: &PlanTarget,
+    model: &dyn CraneModel,
+    max_depth: usize,
+    max_states: usize,
+) -> PlanOutcome {
+    if let PlanTarget::Drawing(target) = target {
+        let crates = |crane: &Crane| {
+            let mut crates = crane
+                .stacks
+                .iter()
+                .flat_map(|stack| stack.crates.iter().cloned())
+                .collect::<Vec<Crate>>();
+            crates.sort();
+            crates
+        };
+
+        if target.labels != start.labels || crates(target) != crates(start) {
+            return PlanOutcome::Unreachable;
+        }
+    }
+
+    // Each state remembers the state it was reached from and how
+    let mut states: Vec<(Crane, Option<(usize, Instruction)>)> = vec![(start.clone(), None)];
+    let mut best: HashMap<Crane, usize> = HashMap::from([(start.clone(), 0)]);
+    let mut frontier = BinaryHeap::from([Reverse((target.estimate(start), 0, 0))]);
+
+    while let Some(Reverse((_, steps, id))) = frontier.pop() {
+        let crane = states[id].0.clone();
+
+        if best.get(&crane).is_some_and(|&known| known < steps) {
+            continue;
+        }
+
+        if target.is_reached(&crane) {
+            let mut instructions = vec![];
+            let mut current = id;
+            while let Some((previous, instruction)) = &states[current].1 {
+                instructions.push(instruction.clone());
+                current = *previous;
+            }
+            instructions.reverse();
+            return PlanOutcome::Found(instructions);
+        }
+
+        if steps == max_depth {
+            continue;
+        }
+
+        for (start, from) in crane.stacks.iter().enumerate() {
+            for target_index in (0..crane.stacks.len()).filter(|&t| t != start) {
+                for amount in 1..=from.crates.len() {
+                    let instruction = Instruction {
+                        start_stack: crane.labels[start],
+                        target_stack: crane.labels[target_index],
+                        crates_to_move: amount,
+                        line: None,
+                    };
+
+                    let mut next = crane.clone();
+                    if next.perform(steps + 1, &instruction, model).is_err() {
+                        continue;
+                    }
+
+                    if best.get(&next).is_some_and(|&known| known <= steps + 1) {
+                        continue;
+                    }
+
+                    if best.len() >= max_states {
+                        return PlanOutcome::StateLimitReached;
+                    }
+
+                    let estimate = steps + 1 + target.estimate(&next);
+                    best.insert(next.clone(), steps + 1);
+                    states.push((next, Some((id, instruction))));
+                    frontier.push(Reverse((estimate, steps + 1, states.len() - 1)));
+                }
+            }
+        }
+    }
+
+    PlanOutcome::Unreachable
+}
+
+/// Draws the stacks the way the puzzle input does, so that parsing the
+/// drawing gives back an identical crane. Columns widen to fit the longest
+/// crate or label
+impl Display for Crane {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let height = self
+            .stacks
+            .iter()
+            .map(|stack| stack.crates.len())
+            .max()
+            .unwrap_or(0);
+
+        let width = self
+            .stacks
+            .iter()
+            .flat_map(|stack| stack.crates.iter())
+            .map(|c| c.chars().count() + 2)
+            .chain(self.labels.iter().map(|label| label.to_string().len()))
+            .fold(3, usize::max);
+
+        for level in (0..height).rev() {
+            let row = self
+                .stacks
+                .iter()
+                .map(|stack| match stack.crates.get(level) {
+                    Some(c) => format!("{:^width$}", format!("[{}]", c)),
+                    None => " ".repeat(width),
+                })
+                .collect::<Vec<String>>();
+            writeln!(f, "{}", row.join(" "))?;
+        }
+
+        let footer = self
+            .labels
+            .iter()
+            .map(|label| format!("{:^width$}", label))
+            .collect::<Vec<String>>();
+        write!(f, "{}", footer.join(" "))
+    }
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse();
+
+    let models = args
+        .models
+        .iter()
+        .map(|model| parse_model(model))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match &args.command {
+        Some(Command::Generate(options)) => {
+            let (crane, instructions) = generate(options)?;
+            println!("{}\n", crane);
+            for instruction in instructions {
+                println!("{}", instruction);
+            }
+            return Ok(());
+        }
+        Some(Command::Bench { options, baseline }) => {
+            let (crane, instructions) = generate(options)?;
+
+            for model in models.iter() {
+                let timer = Instant::now();
+                let results = simulate(&crane, &instructions, std::slice::from_ref(model))?;
+                println!(
+                    "{} over {} instructions and {} crates with the rope: {:?}",
+                    model.name(),
+                    instructions.len(),
+                    options.crates,
+                    timer.elapsed()
+                );
+
+                if *baseline {
+                    let timer = Instant::now();
+                    let mut vector_crane = crane.clone();
+                    for (number, instruction) in instructions.iter().enumerate() {
+                        vector_crane.perform(number + 1, instruction, model.as_ref())?;
+                    }
+                    println!("  and with vectors: {:?}", timer.elapsed());
+
+                    if vector_crane.display_top_crates() != results[0].top_crates {
+                        bail!("The rope and vector simulations disagree");
+                    }
+                }
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let input = match &args.input {
+        Some(path) => {
+            fs::read_to_string(path).with_context(|| format!("Couldn't read {}", path.display()))?
+        }
+        None => String::from(include_str!("../inputs/day05.txt")),
+    };
+
+    // An input without instructions is just a drawing
+    let (drawing_str, instructions_str) = match input.split_once("\n\n") {
+        Some(parts) => parts,
+        None => (input.as_str(), ""),
+    };
+
+    let mut instructions = vec![];
+    let mut skipped = 0;
+
+    // The drawing and the blank line come before the first instruction
+    let first_line = drawing_str.lines().count() + 2;
+
+    for (number, line) in instructions_str.lines().enumerate() {
+        let instruction = if args.strict {
+            line.parse::<Instruction>()
+        } else {
+            Instruction::parse_lenient(line)
+        };
+
+        match instruction {
+            Ok(instruction) => instructions.push(Instruction {
+                line: Some(first_line + number),
+                ..instruction
+            }),
+            Err(e) if args.strict => {
+                bail!("Line {} ({:?}): {}", first_line + number, line, e)
+            }
+            Err(_) => skipped += 1,
+        }
+    }
+
+    if skipped > 0 {
+        eprintln!("Skipped {} unparsable instruction lines", skipped);
+    }
+
+    if let Some(Command::Unsolve { final_drawing }) = &args.command {
+        let final_crane = fs::read_to_string(final_drawing)
+            .with_context(|| format!("Couldn't read {}", final_drawing.display()))?
+            .parse::<Crane>()?;
+
+        for model in models.iter() {
+            let start = unsolve(&final_crane, &instructions, model.as_ref())?;
+
+            println!("Starting stacks for the {}:\n{}\n", model.name(), start);
+            for origin in unclear_origins(&final_crane, &instructions, model.as_ref())? {
+                println!("{}", origin);
+            }
+        }
+
+        return Ok(());
+    }
+
+    let crane = drawing_str.parse::<Crane>()?;
+
+    if let Some(Command::Plan {
+        target,
+        top,
+        max_depth,
+        max_states,
+    }) = &args.command
+    {
+        let target = match (target, top) {
+            (Some(path), _) => PlanTarget::Drawing(
+                fs::read_to_string(path)
+                    .with_context(|| format!("Couldn't read {}", path.display()))?
+                    .parse::<Crane>()?,
+            ),
+            (None, Some(top)) => PlanTarget::TopCrates(top.clone()),
+            (None, None) => bail!("A target drawing or top crates are required"),
+        };
+
+        for model in models.iter() {
+            match plan(&crane, &target, model.as_ref(), *max_depth, *max_states) {
+                PlanOutcome::Found(instructions) => {
+                    println!(
+                        "Shortest plan for the {} takes {} instructions:",
+                        model.name(),
+                        instructions.len()
+                    );
+                    for instruction in instructions {
+                        println!("{}", instruction);
+                    }
+                }
+                PlanOutcome::Unreachable => println!(
+                    "The {} cannot reach the target within {} instructions",
+                    model.name(),
+                    max_depth
+                ),
+                PlanOutcome::StateLimitReached => println!(
+                    "The {} search gave up after {} arrangements",
+                    model.name(),
+                    max_states
+                ),
+            }
+        }
+
+        return Ok(());
+    }
+
+    let results = simulate(&crane, &instructions, &models)?;
+
+    for result in results.iter() {
+        println!(
+            "Crates on top of each stack when using the {}: {} ({} lifts, cost {})",
+            result.model, result.top_crates, result.lifts, result.cost
+        );
+    }
+
+    if results.len() > 1 {
+        if results
+            .iter()
+            .all(|result| result.top_crates == results[0].top_crates)
+        {
+            println!("All models leave the same crates on top");
+        } else {
+            println!("The models leave different crates on top");
+        }
+    }
+
+    let mut histories = models
+        .iter()
+        .map(|model| CraneHistory::new(crane.clone(), model.as_ref(), &instructions))
+        .collect::<Vec<CraneHistory>>();
+
+    if let Some(number) = args.compare_at {
+        histories[0].jump_to(number)?;
+        let reference = histories[0].snapshot();
+
+        for (model, history) in models.iter().zip(histories.iter_mut()).skip(1) {
+            let diff = reference.diff(history.jump_to(number)?);
+            println!(
+                "After instruction {}, the {} differs from the {} in {} stacks",
+                number,
+                model.name(),
+                models[0].name(),
+                diff.len()
+            );
+            for stack in diff {
+                println!("  {}", stack);
+            }
+        }
+    }
+
+    if args.first_divergence {
+        for history in histories.iter_mut() {
+            history.jump_to(0)?;
+        }
+
+        let mut divergence = None;
+        for number in 1..=instructions.len() {
+            for history in histories.iter_mut() {
+                history.step_forward()?;
+            }
+
+            let top_crates = histories[0].crane.display_top_crates();
+            if histories[1..]
+                .iter()
+                .any(|history| history.crane.display_top_crates() != top_crates)
+            {
+                divergence = Some(number);
+                break;
+            }
+        }
+
+        match divergence {
+            Some(number) => println!(
+                "Top crates first differ after instruction {}: {:?}",
+                number,
+                histories
+                    .iter()
+                    .map(|history| history.crane.display_top_crates())
+                    .collect::<Vec<String>>()
+            ),
+            None => println!("Top crates never differ between the models"),
+        }
+    }
+
+    if args.render {
+        for history in histories.iter_mut() {
+            let crane = history.jump_to(instructions.len())?;
+            let drawing = crane.to_string();
+
+            if drawing.parse::<Crane>()? != *crane {
+                bail!(
+                    "The drawing of the {} does not parse back",
+                    history.model.name()
+                );
+            }
+
+            println!("{}:\n{}\n", history.model.name(), drawing);
+        }
+    }
+
+    if args.animate {
+        let history = &mut histories[0];
+        history.jump_to(0)?;
+
+        loop {
+            // Clear the screen and move the cursor to the top left corner
+            print!("\x1b[2J\x1b[H");
+            match history.position.checked_sub(1) {
+                Some(index) => println!(
+                    "{} after instruction {}: {}\n",
+                    history.model.name(),
+                    history.position,
+                    instructions[index]
+                ),
+                None => println!("{} before any instructions\n", history.model.name()),
+            }
+            println!("{}", history.crane);
+
+            if !history.step_forward()? {
+                break;
+            }
+            thread::sleep(Duration::from_millis(args.frame_delay));
+        }
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crane(stacks: &[(usize, &[&str])]) -> Crane {
+        Crane::from(
+            stacks.iter().map(|(label, _)| *label).collect(),
+            stacks
+                .iter()
+                .map(|(_, crates)| Stack {
+                    crates: crates.iter().map(|c| String::from(*c)).collect(),
+                })
+                .collect(),
+        )
+    }
+
+    fn assert_round_trips(crane: &Crane) {
+        let drawing = crane.to_string();
+        let parsed = drawing
+            .parse::<Crane>()
+            .unwrap_or_else(|e| panic!("Could not parse\n{}\n{}", drawing, e));
+        assert_eq!(parsed, *crane, "Drawing did not round trip\n{}", drawing);
+    }
+
+    #[test]
+    fn input_drawing_round_trips() {
+        let input = include_str!("../inputs/day05.txt");
+        let (drawing, _) = input.split_once("\n\n").unwrap();
+        assert_round_trips(&drawing.parse::<Crane>().unwrap());
+    }
+
+    #[test]
+    fn multi_character_crates_round_trip() {
+        assert_round_trips(&crane(&[
+            (1, &["AB", "C", "LONGEST"]),
+            (2, &["D"]),
+            (3, &["EFG", "H"]),
+        ]));
+    }
+
+    #[test]
+    fn multi_digit_labels_round_trip() {
+        assert_round_trips(&crane(&[(9, &["A"]), (10, &["B", "C"]), (123, &["D"])]));
+        assert_round_trips(&crane(&[(12345, &["A", "B"]), (7, &["C"])]));
+    }
+
+    #[test]
+    fn empty_stacks_round_trip() {
+        assert_round_trips(&crane(&[(1, &[]), (2, &["A"]), (3, &[])]));
+        assert_round_trips(&crane(&[(4, &[]), (5, &[])]));
+    }
+
+    #[test]
+    fn irregular_cranes_round_trip() {
+        assert_round_trips(&crane(&[
+            (3, &[]),
+            (11, &["XY", "?", "Z"]),
+            (1, &["Q"]),
+            (100, &[]),
+            (27, &["LONG", "A", "BC", "D"]),
+        ]));
+    }
+
+    fn models() -> Vec<Box<dyn CraneModel>> {
+        ["9000", "9001", "limited-2"]
+            .iter()
+            .map(|model| parse_model(model).unwrap())
+            .collect()
+    }
+
+    /// The drawing and instructions from the puzzle description
+    fn example() -> (Crane, Vec<Instruction>) {
+        let crane = crane(&[(1, &["Z", "N"]), (2, &["M", "C", "D"]), (3, &["P"])]);
+        let instructions = [
+            "move 1 from 2 to 1",
+            "move 3 from 1 to 3",
+            "move 2 from 2 to 1",
+            "move 1 from 1 to 2",
+        ]
+        .iter()
+        .map(|line| line.parse::<Instruction>().unwrap())
+        .collect();
+        (crane, instructions)
+    }
+
+    fn run_forward(start: &Crane, instructions: &[Instruction], model: &dyn CraneModel) -> Crane {
+        let mut crane = start.clone();
+        for (number, instruction) in instructions.iter().enumerate() {
+            crane.perform(number + 1, instruction, model).unwrap();
+        }
+        crane
+    }
+
+    #[test]
+    fn unsolved_stacks_lead_back_to_the_final_stacks() {
+        let (start, instructions) = example();
+
+        for model in models() {
+            let final_crane = run_forward(&start, &instructions, model.as_ref());
+            let unsolved = unsolve(&final_crane, &instructions, model.as_ref()).unwrap();
+
+            assert_eq!(unsolved, start, "{}", model.name());
+            assert_eq!(
+                run_forward(&unsolved, &instructions, model.as_ref()),
+                final_crane,
+                "{}",
+                model.name()
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_crates_are_traced_to_where_they_end_up() {
+        let (mut start, instructions) = example();
+        // Stack 2, crate 2 from the bottom is the only one labelled X
+        start.stacks[1].crates[1] = String::from("X");
+
+        for model in models() {
+            let mut final_crane = run_forward(&start, &instructions, model.as_ref());
+
+            let (end_stack, end_level) = final_crane
+                .stacks
+                .iter()
+                .enumerate()
+                .find_map(|(index, stack)| {
+                    let level = stack.crates.iter().position(|c| c == "X")?;
+                    Some((index, level))
+                })
+                .unwrap();
+            final_crane.stacks[end_stack].crates[end_level] = String::from(UNKNOWN_CRATE);
+
+            assert_eq!(
+                unclear_origins(&final_crane, &instructions, model.as_ref()).unwrap(),
+                vec![format!(
+                    "Stack 2, crate 2 from the bottom is unconstrained (it ends up as stack {}, crate {} from the bottom)",
+                    final_crane.labels[end_stack],
+                    end_level + 1
+                )],
+                "{}",
+                model.name()
+            );
+        }
+    }
+
+    #[test]
+    fn planned_instructions_reach_the_target() {
+        let (start, instructions) = example();
+
+        for model in models() {
+            let target = run_forward(&start, &instructions, model.as_ref());
+            let targets = [
+                PlanTarget::TopCrates(target.display_top_crates()),
+                PlanTarget::Drawing(target),
+            ];
+
+            for target in targets.iter() {
+                match plan(&start, target, model.as_ref(), instructions.len(), 100_000) {
+                    PlanOutcome::Found(planned) => {
+                        assert!(planned.len() <= instructions.len());
+                        assert!(
+                            target.is_reached(&run_forward(&start, &planned, model.as_ref())),
+                            "{}: {:?} does not reach {:?}",
+                            model.name(),
+                            planned,
+                            target
+                        );
+                    }
+                    outcome => panic!("{}: {:?} for {:?}", model.name(), outcome, target),
+                }
+            }
+        }
+    }
+
+    /// Reads every stack out of the rope, bottom crate first
+    fn rope_stacks(rope: &mut CrateRope) -> Vec<Stack> {
+        fn collect(rope: &mut CrateRope, node: usize, crates: &mut Vec<Crate>) {
+            if node == NIL {
+                return;
+            }
+            rope.push_down(node);
+            let (left, right) = (rope.nodes[node].left, rope.nodes[node].right);
+            collect(rope, left, crates);
+            crates.push(rope.names[rope.nodes[node].crate_id as usize].clone());
+            collect(rope, right, crates);
+        }
+
+        (0..rope.roots.len())
+            .map(|index| {
+                let mut crates = vec![];
+                collect(rope, rope.roots[index], &mut crates);
+                Stack { crates }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rope_matches_the_vector_crane() {
+        let mut models = models();
+        models.push(parse_model("limited-3").unwrap());
+
+        for seed in 1..=20 {
+            let options = GeneratorOptions {
+                stacks: 2 + seed as usize % 4,
+                crates: 5 * seed as usize,
+                instructions: 200,
+                max_move: 1 + seed as usize % 7,
+                seed,
+            };
+            let (start, instructions) = generate(&options).unwrap();
+
+            for model in models.iter() {
+                let mut rope = CrateRope::from(&start);
+                for (number, instruction) in instructions.iter().enumerate() {
+                    rope.perform(number + 1, instruction, model.as_ref())
+                        .unwrap();
+                }
+
+                assert_eq!(
+                    rope_stacks(&mut rope),
+                    run_forward(&start, &instructions, model.as_ref()).stacks,
+                    "{} with seed {}",
+                    model.name(),
+                    seed
+                );
+            }
+        }
+    }
+}
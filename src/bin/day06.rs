@@ -1,38 +1,597 @@
-use std::collections::HashSet;
+use anyhow::{anyhow, bail, ensure, Context};
+use clap::Parser;
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Write},
+    iter,
+    path::PathBuf,
+    str::FromStr,
+};
 
-fn find_first_start_of_marker(
-    datastream: &Vec<char>,
-    num_distinct_characters: usize,
-) -> Option<usize> {
-    datastream
-        .windows(num_distinct_characters)
-        .position(|window| window.iter().collect::<HashSet<_>>().len() == num_distinct_characters)
-        .map(|position| position + num_distinct_characters)
+#[derive(Debug, Parser)]
+struct Args {
+    /// Read a datastream of any length from this file, or from stdin if it
+    /// is `-`, and report markers as they are found instead of searching
+    /// the puzzle input
+    #[arg(long)]
+    stream: Option<PathBuf>,
+
+    /// Decode the whole datastream into frames, each starting at a marker
+    /// given by `--marker` and running up to the next one of the same kind
+    #[arg(long)]
+    frames: bool,
+
+    /// Markers to look for, as `name=length`, optionally followed by
+    /// `,alphabet=<bytes>` to only accept windows made of those bytes and
+    /// `,overlap` to let one match start inside the previous one
+    #[arg(long = "marker", default_values = ["packet=4", "message=14"])]
+    markers: Vec<MarkerSpec>,
+
+    /// Report every position of each marker rather than just the first
+    #[arg(long)]
+    all: bool,
+
+    /// Bytes to read from the stream at a time
+    #[arg(long, default_value_t = 4096)]
+    chunk_size: usize,
+}
+
+/// Describes one kind of marker a device protocol uses
+#[derive(Debug, Clone)]
+struct MarkerSpec {
+    name: String,
+    length: usize,
+    alphabet: Option<Vec<u8>>,
+    overlap: bool,
+}
+
+impl MarkerSpec {
+    fn new(name: &str, length: usize) -> MarkerSpec {
+        MarkerSpec {
+            name: name.to_string(),
+            length,
+            alphabet: None,
+            overlap: false,
+        }
+    }
+}
+
+impl FromStr for MarkerSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options = s.split(',');
+        let (name, length) = options
+            .next()
+            .and_then(|spec| spec.split_once('='))
+            .ok_or_else(|| anyhow!("Expected <name>=<length>, found {:?}", s))?;
+
+        ensure!(!name.is_empty(), "Marker in {:?} has no name", s);
+        let length = length
+            .parse::<usize>()
+            .map_err(|e| anyhow!("Invalid length for marker {:?}: {}", name, e))?;
+        ensure!(
+            (1..=256).contains(&length),
+            "Marker {:?} must be between 1 and 256 bytes long",
+            name
+        );
+
+        let mut spec = MarkerSpec::new(name, length);
+        for option in options {
+            match option.split_once('=') {
+                Some(("alphabet", bytes)) => {
+                    let mut distinct = bytes.as_bytes().to_vec();
+                    distinct.sort_unstable();
+                    distinct.dedup();
+                    ensure!(
+                        distinct.len() >= length,
+                        "Alphabet of marker {:?} has too few distinct bytes for a window of {}",
+                        name,
+                        length
+                    );
+                    spec.alphabet = Some(bytes.as_bytes().to_vec());
+                }
+                None if option == "overlap" => spec.overlap = true,
+                _ => bail!("Unknown option {:?} for marker {:?}", option, name),
+            }
+        }
+
+        Ok(spec)
+    }
+}
+
+/// Watches a datastream one byte at a time for a window of distinct bytes.
+/// It keeps the last window in a ring buffer along with a count of every
+/// byte in it and of how many bytes appear more than once, so each byte
+/// costs O(1) however long the marker is, and input can arrive in pieces of
+/// any size. A marker longer than 256 can never be found, as there are only
+/// that many distinct bytes
+#[derive(Debug)]
+struct MarkerDetector {
+    window: Vec<u8>,
+    counts: [usize; 256],
+    repeated: usize,
+    /// Bytes in the window so far, at most the marker length
+    filled: usize,
+    /// Bytes seen since the start of the datastream
+    offset: usize,
+    /// Bytes a window may contain, or `None` for any
+    allowed: Option<[bool; 256]>,
+    overlap: bool,
+}
+
+impl MarkerDetector {
+    fn new(num_distinct_characters: usize) -> MarkerDetector {
+        MarkerDetector {
+            window: vec![0; num_distinct_characters],
+            counts: [0; 256],
+            repeated: 0,
+            filled: 0,
+            offset: 0,
+            allowed: None,
+            overlap: false,
+        }
+    }
+
+    fn for_spec(spec: &MarkerSpec) -> MarkerDetector {
+        let mut detector = MarkerDetector::new(spec.length);
+        detector.overlap = spec.overlap;
+        detector.allowed = spec.alphabet.as_ref().map(|alphabet| {
+            let mut allowed = [false; 256];
+            for &byte in alphabet {
+                allowed[byte as usize] = true;
+            }
+            allowed
+        });
+        detector
+    }
+
+    fn reset(&mut self) {
+        self.counts = [0; 256];
+        self.repeated = 0;
+        self.filled = 0;
+    }
+
+    /// Feeds the next byte, returning the offset just past the marker if
+    /// this byte completes one. Unless matches may overlap, the window then
+    /// starts afresh. A byte outside the alphabet can't be part of any
+    /// marker, so it empties the window as well
+    fn push(&mut self, byte: u8) -> Option<usize> {
+        let length = self.window.len();
+        if length == 0 || length > 256 {
+            self.offset += 1;
+            return None;
+        }
+
+        if self.allowed.is_some_and(|allowed| !allowed[byte as usize]) {
+            self.reset();
+            self.offset += 1;
+            return None;
+        }
+
+        let slot = self.offset % length;
+        if self.filled == length {
+            let leaving = self.window[slot] as usize;
+            if self.counts[leaving] == 2 {
+                self.repeated -= 1;
+            }
+            self.counts[leaving] -= 1;
+        } else {
+            self.filled += 1;
+        }
+
+        self.window[slot] = byte;
+        self.counts[byte as usize] += 1;
+        if self.counts[byte as usize] == 2 {
+            self.repeated += 1;
+        }
+        self.offset += 1;
+
+        if self.filled == length && self.repeated == 0 {
+            if !self.overlap {
+                self.reset();
+            }
+            return Some(self.offset);
+        }
+
+        None
+    }
+}
+
+/// Looks for every spec in a single pass over the datastream, returning
+/// the end offsets of the first or of all matches of each. Stops early once
+/// every spec has been found if only the first ones are wanted
+fn find_markers(datastream: &[u8], specs: &[MarkerSpec], all: bool) -> Vec<Vec<usize>> {
+    let mut detectors = specs
+        .iter()
+        .map(MarkerDetector::for_spec)
+        .collect::<Vec<MarkerDetector>>();
+    let mut found = vec![Vec::new(); specs.len()];
+    let mut remaining = specs.len();
+
+    for &byte in datastream {
+        for (detector, positions) in detectors.iter_mut().zip(found.iter_mut()) {
+            if !all && !positions.is_empty() {
+                continue;
+            }
+
+            if let Some(offset) = detector.push(byte) {
+                if positions.is_empty() {
+                    remaining -= 1;
+                }
+                positions.push(offset);
+            }
+        }
+
+        if !all && remaining == 0 {
+            break;
+        }
+    }
+
+    found
+}
+
+/// Reads the datastream in chunks of `chunk_size` bytes, calling `report`
+/// with the spec and offset of the first or of every marker as soon as it
+/// is read. Returns the number of bytes read, which stops short of the end
+/// of the stream once every spec has been found if only the first ones are
+/// wanted
+fn stream_markers(
+    reader: &mut impl Read,
+    specs: &[MarkerSpec],
+    all: bool,
+    chunk_size: usize,
+    mut report: impl FnMut(&MarkerSpec, usize) -> Result<(), anyhow::Error>,
+) -> Result<usize, anyhow::Error> {
+    let mut detectors = specs
+        .iter()
+        .map(MarkerDetector::for_spec)
+        .collect::<Vec<MarkerDetector>>();
+    let mut found = vec![false; specs.len()];
+    let mut remaining = specs.len();
+    let mut chunk = vec![0; chunk_size.max(1)];
+    let mut total = 0;
+
+    loop {
+        let read = match reader.read(&mut chunk) {
+            Ok(0) => return Ok(total),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        for (count, &byte) in chunk[..read].iter().enumerate() {
+            for ((detector, spec), found) in detectors.iter_mut().zip(specs).zip(found.iter_mut()) {
+                if !all && *found {
+                    continue;
+                }
+
+                if let Some(offset) = detector.push(byte) {
+                    if !*found {
+                        *found = true;
+                        remaining -= 1;
+                    }
+                    report(spec, offset)?;
+                }
+            }
+
+            if !all && remaining == 0 {
+                return Ok(total + count + 1);
+            }
+        }
+        total += read;
+    }
+}
+
+/// A marker and everything after it up to the next marker of the same kind,
+/// or the end of the datastream
+#[derive(Debug, Clone, Copy)]
+struct Frame<'a> {
+    /// Index of the marker spec that started the frame
+    kind: usize,
+    name: &'a str,
+    marker_start: usize,
+    payload_start: usize,
+    payload: &'a [u8],
+}
+
+impl Frame<'_> {
+    fn payload_end(&self) -> usize {
+        self.payload_start + self.payload.len()
+    }
+}
+
+impl fmt::Display for Frame<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<7} marker {}..{} payload {}..{} ({} bytes): {}",
+            self.name,
+            self.marker_start,
+            self.payload_start,
+            self.payload_start,
+            self.payload_end(),
+            self.payload.len(),
+            String::from_utf8_lossy(self.payload)
+        )
+    }
+}
+
+/// Yields the frames of one kind in order. Each marker is only known to end
+/// a payload once the following one has been found, so the decoder always
+/// stays one marker ahead of what it has yielded
+struct FrameDecoder<'a> {
+    datastream: &'a [u8],
+    kind: usize,
+    spec: &'a MarkerSpec,
+    detector: MarkerDetector,
+    /// End of the marker starting the next frame to yield
+    next_marker_end: Option<usize>,
+}
+
+impl<'a> FrameDecoder<'a> {
+    fn new(datastream: &'a [u8], kind: usize, spec: &'a MarkerSpec) -> FrameDecoder<'a> {
+        let mut decoder = FrameDecoder {
+            datastream,
+            kind,
+            spec,
+            detector: MarkerDetector::for_spec(spec),
+            next_marker_end: None,
+        };
+        decoder.next_marker_end = decoder.find_next_marker();
+        decoder
+    }
+
+    fn find_next_marker(&mut self) -> Option<usize> {
+        while let Some(&byte) = self.datastream.get(self.detector.offset) {
+            if let Some(end) = self.detector.push(byte) {
+                return Some(end);
+            }
+        }
+
+        None
+    }
+}
+
+impl<'a> Iterator for FrameDecoder<'a> {
+    type Item = Frame<'a>;
+
+    fn next(&mut self) -> Option<Frame<'a>> {
+        let payload_start = self.next_marker_end?;
+        self.next_marker_end = self.find_next_marker();
+
+        // An overlapping marker can start inside the previous one, leaving
+        // that frame without a payload
+        let marker_length = self.spec.length;
+        let payload_end = match self.next_marker_end {
+            Some(end) => (end - marker_length).max(payload_start),
+            None => self.datastream.len(),
+        };
+
+        Some(Frame {
+            kind: self.kind,
+            name: &self.spec.name,
+            marker_start: payload_start - marker_length,
+            payload_start,
+            payload: &self.datastream[payload_start..payload_end],
+        })
+    }
+}
+
+/// Every frame in the datastream, each started by a marker of one of the
+/// specs, ordered by where their markers start
+fn frames<'a>(datastream: &'a [u8], specs: &'a [MarkerSpec]) -> impl Iterator<Item = Frame<'a>> {
+    let mut decoders = specs
+        .iter()
+        .enumerate()
+        .map(|(kind, spec)| FrameDecoder::new(datastream, kind, spec).peekable())
+        .collect::<Vec<_>>();
+
+    iter::from_fn(move || {
+        decoders
+            .iter_mut()
+            .filter_map(|decoder| Some((decoder.peek()?.marker_start, decoder)))
+            .min_by_key(|(marker_start, _)| *marker_start)
+            .and_then(|(_, decoder)| decoder.next())
+    })
 }
 
 fn main() -> Result<(), anyhow::Error> {
-    let datastream = include_str!("../inputs/day06.txt")
-        .chars()
-        .collect::<Vec<char>>();
-
-    let first_start_of_packet_marker = match find_first_start_of_marker(&datastream, 4) {
-        Some(value) => value,
-        _ => unreachable!("Shouldn't happen. Couldn't find start of packet marker"),
-    };
-
-    let first_start_of_message_marker = match find_first_start_of_marker(&datastream, 14) {
-        Some(value) => value,
-        _ => unreachable!("Shouldn't happen. Couldn't find start of message marker"),
-    };
-
-    println!(
-        "Start of first packet marker: {}",
-        first_start_of_packet_marker
-    );
-    println!(
-        "Start of first message marker: {}",
-        first_start_of_message_marker
-    );
+    let args = Args::parse();
+
+    if let Some(path) = &args.stream {
+        let mut reader: Box<dyn Read> = if path.as_os_str() == "-" {
+            Box::new(io::stdin().lock())
+        } else {
+            Box::new(File::open(path).with_context(|| format!("Couldn't read {}", path.display()))?)
+        };
+
+        let mut stdout = io::stdout().lock();
+        let total = stream_markers(
+            &mut reader,
+            &args.markers,
+            args.all,
+            args.chunk_size,
+            |spec, offset| {
+                writeln!(stdout, "Marker {} ends at {}", spec.name, offset)?;
+                stdout.flush()?;
+                Ok(())
+            },
+        )?;
+
+        println!("Read {} bytes", total);
+        return Ok(());
+    }
+
+    let datastream = include_str!("../inputs/day06.txt").trim_end().as_bytes();
+
+    let found = find_markers(datastream, &args.markers, args.all);
+    for (spec, positions) in args.markers.iter().zip(found) {
+        match positions.as_slice() {
+            [] => println!("No {} marker found", spec.name),
+            [first] if !args.all => println!("Start of first {} marker: {}", spec.name, first),
+            positions => println!(
+                "{} {} markers: {}",
+                positions.len(),
+                spec.name,
+                positions
+                    .iter()
+                    .map(|position| position.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+        }
+    }
+
+    if args.frames {
+        let mut counts = vec![0; args.markers.len()];
+        for frame in frames(datastream, &args.markers) {
+            counts[frame.kind] += 1;
+            println!("{}", frame);
+        }
+
+        let summary = args
+            .markers
+            .iter()
+            .zip(counts)
+            .map(|(spec, count)| format!("{} {} frames", count, spec.name))
+            .collect::<Vec<String>>();
+        println!("Decoded {}", summary.join(", "));
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn find_first_start_of_marker(datastream: &[u8], length: usize) -> Option<usize> {
+        find_markers(datastream, &[MarkerSpec::new("marker", length)], false)[0]
+            .first()
+            .copied()
+    }
+
+    /// Reference implementation, building a set for every window
+    fn find_first_start_of_marker_naive(
+        datastream: &[char],
+        num_distinct_characters: usize,
+    ) -> Option<usize> {
+        datastream
+            .windows(num_distinct_characters)
+            .position(|window| {
+                window.iter().collect::<HashSet<_>>().len() == num_distinct_characters
+            })
+            .map(|position| position + num_distinct_characters)
+    }
+
+    /// Compares both detectors for a range of marker lengths. The naive one
+    /// works on chars, so the datastreams are kept to ASCII
+    fn assert_matches_reference(datastream: &[u8]) {
+        let chars = datastream.iter().map(|&b| b as char).collect::<Vec<char>>();
+
+        for length in 1..=32 {
+            assert_eq!(
+                find_first_start_of_marker(datastream, length),
+                find_first_start_of_marker_naive(&chars, length),
+                "Marker length {} in {:?}",
+                length,
+                String::from_utf8_lossy(datastream)
+            );
+        }
+    }
+
+    #[test]
+    fn rolling_detector_matches_reference_on_input() {
+        assert_matches_reference(include_str!("../inputs/day06.txt").trim_end().as_bytes());
+    }
+
+    #[test]
+    fn rolling_detector_matches_reference_on_generated_streams() {
+        // Small alphabets give plenty of repeats, large ones plenty of markers
+        let mut state: u64 = 0x2022;
+        for alphabet in [2u64, 4, 8, 16, 26, 40] {
+            for length in [0, 1, 5, 30, 200] {
+                let datastream = (0..length)
+                    .map(|_| {
+                        state ^= state << 13;
+                        state ^= state >> 7;
+                        state ^= state << 17;
+                        b'0' + (state % alphabet) as u8
+                    })
+                    .collect::<Vec<u8>>();
+                assert_matches_reference(&datastream);
+            }
+        }
+    }
+
+    #[test]
+    fn puzzle_examples() {
+        let examples = [
+            ("mjqjpqmgbljsphdztnvjfqwrcgsmlb", 7, 19),
+            ("bvwbjplbgvbhsrlpgdmjqwftvncz", 5, 23),
+            ("nppdvjthqldpwncqszvftbrmjlhg", 6, 23),
+            ("nznrnfrfntjfmvfwmzdfjlvtqnbhcprsg", 10, 29),
+            ("zcfzfwzzqfrljwzlrfnpqdbhtmscgvjw", 11, 26),
+        ];
+
+        for (datastream, packet, message) in examples {
+            assert_eq!(
+                find_first_start_of_marker(datastream.as_bytes(), 4),
+                Some(packet)
+            );
+            assert_eq!(
+                find_first_start_of_marker(datastream.as_bytes(), 14),
+                Some(message)
+            );
+        }
+    }
+
+    /// Runs the datastream through `stream_markers`, collecting the offsets
+    /// reported for each spec
+    fn streamed_markers(
+        datastream: &[u8],
+        specs: &[MarkerSpec],
+        all: bool,
+        chunk_size: usize,
+    ) -> Vec<Vec<usize>> {
+        let mut found = vec![Vec::new(); specs.len()];
+        let mut reader = datastream;
+        stream_markers(&mut reader, specs, all, chunk_size, |spec, offset| {
+            let index = specs.iter().position(|s| s.name == spec.name).unwrap();
+            found[index].push(offset);
+            Ok(())
+        })
+        .unwrap();
+        found
+    }
+
+    #[test]
+    fn streamed_markers_match_across_chunk_boundaries() {
+        let datastream = include_str!("../inputs/day06.txt").trim_end().as_bytes();
+        let specs = [
+            "packet=4",
+            "message=14",
+            "pair=2,overlap",
+            "digits=3,alphabet=abcdefghij",
+        ]
+        .map(|spec| spec.parse::<MarkerSpec>().unwrap());
+
+        for all in [false, true] {
+            let expected = find_markers(datastream, &specs, all);
+            assert!(expected.iter().all(|positions| !positions.is_empty()));
+
+            for chunk_size in [1, 3, 4096] {
+                assert_eq!(
+                    streamed_markers(datastream, &specs, all, chunk_size),
+                    expected,
+                    "all = {}, chunk size {}",
+                    all,
+                    chunk_size
+                );
+            }
+        }
+    }
+}
@@ -1,94 +1,1243 @@
-use std::{collections::HashSet, str::FromStr};
+use anyhow::{anyhow, bail, ensure, Context};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-#[derive(Debug)]
-struct File {
-    size: usize,
-    path: String,
+#[derive(Debug, Parser)]
+struct Args {
+    /// Terminal log to reconstruct the filesystem from
+    #[arg(long, default_value = "src/inputs/day07.txt")]
+    input: PathBuf,
+
+    /// Print the filesystem as a tree with the size of every node
+    #[arg(long)]
+    tree: bool,
+
+    #[command(flatten)]
+    tree_options: TreeOptions,
+
+    /// Total size of the device's disk in bytes
+    #[arg(long, default_value_t = 70_000_000)]
+    disk_size: usize,
+
+    /// Free space the update needs in bytes
+    #[arg(long, default_value_t = 30_000_000)]
+    update_size: usize,
+
+    /// Largest directory counted in the sum of small directories
+    #[arg(long, default_value_t = 100_000)]
+    small_dir_limit: usize,
+
+    /// Find the set of files and directories that frees up the space needed
+    /// while deleting as few bytes as possible
+    #[arg(long)]
+    plan_deletion: bool,
+
+    /// Path that the deletion plan must leave in place, along with
+    /// everything in and above it
+    #[arg(long = "protect")]
+    protected: Vec<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// List the files and directories matching all the given tests, with
+    /// their sizes
+    Find(FindOptions),
+    /// Recreate the filesystem on disk, with sparse files of the right sizes
+    Materialise {
+        /// Directory to create it in, which mustn't exist yet. Defaults to a
+        /// new one in the system's temporary directory
+        into: Option<PathBuf>,
+    },
+    /// Print a terminal log of browsing a real directory, in the same form
+    /// as the puzzle input
+    Transcript {
+        /// Directory to treat as the root
+        dir: PathBuf,
+    },
+}
+
+#[derive(Debug, clap::Args)]
+struct FindOptions {
+    /// Directory to search below
+    #[arg(default_value = "/")]
+    start: String,
+
+    /// Glob the full path must match. `*` and `?` stay within one name,
+    /// `**` spans any number of directories. Repeat to accept any of them
+    #[arg(long)]
+    path: Vec<String>,
+
+    /// Size in bytes, more than `+N`, less than `-N` or exactly `N`, with an
+    /// optional k, M or G suffix. Repeat to narrow it down
+    #[arg(long, allow_hyphen_values = true)]
+    size: Vec<SizeTest>,
+
+    /// Only files (`f`) or only directories (`d`)
+    #[arg(long = "type", value_enum)]
+    kind: Option<EntryType>,
+
+    /// File extension, without the dot. Repeat to accept any of them
+    #[arg(long = "ext")]
+    extensions: Vec<String>,
+
+    /// Skip anything less than this many levels below the start
+    #[arg(long, default_value_t = 0)]
+    min_depth: usize,
+
+    /// Don't go more than this many levels below the start
+    #[arg(long)]
+    max_depth: Option<usize>,
 }
 
-impl FromStr for File {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum EntryType {
+    #[value(name = "f")]
+    File,
+    #[value(name = "d")]
+    Dir,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SizeTest {
+    Over(usize),
+    Under(usize),
+    Exactly(usize),
+}
+
+impl SizeTest {
+    fn matches(self, size: usize) -> bool {
+        match self {
+            SizeTest::Over(limit) => size > limit,
+            SizeTest::Under(limit) => size < limit,
+            SizeTest::Exactly(limit) => size == limit,
+        }
+    }
+}
+
+impl FromStr for SizeTest {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let size = match s.split_once(" ") {
-            Some((size, _)) => {
-                let file_size = size.parse::<usize>()?;
-                file_size
+        let (test, amount): (fn(usize) -> SizeTest, &str) = match s.as_bytes().first() {
+            Some(b'+') => (SizeTest::Over, &s[1..]),
+            Some(b'-') => (SizeTest::Under, &s[1..]),
+            _ => (SizeTest::Exactly, s),
+        };
+
+        let (digits, unit) = match amount.char_indices().last() {
+            Some((index, c)) if c.is_ascii_alphabetic() => (&amount[..index], c),
+            _ => (amount, 'c'),
+        };
+        let unit = match unit.to_ascii_lowercase() {
+            'c' => 1,
+            'k' => 1 << 10,
+            'm' => 1 << 20,
+            'g' => 1 << 30,
+            _ => bail!("Unknown size unit {:?} in {:?}", unit, s),
+        };
+
+        let amount = digits
+            .parse::<usize>()
+            .map_err(|e| anyhow!("Invalid size {:?}: {}", s, e))?;
+
+        amount
+            .checked_mul(unit)
+            .map(test)
+            .ok_or_else(|| anyhow!("Size {:?} is too large", s))
+    }
+}
+
+/// Matches a glob against a whole path. A `**/` can also stand for no
+/// directories at all, so `/a/**/*.dat` matches `/a/x.dat`
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', b'/', rest @ ..] => {
+            glob_matches(rest, text)
+                || (0..text.len())
+                    .filter(|&index| text[index] == b'/')
+                    .any(|index| glob_matches(rest, &text[index + 1..]))
+        }
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|index| glob_matches(rest, &text[index..])),
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&index| index == 0 || text[index - 1] != b'/')
+            .any(|index| glob_matches(rest, &text[index..])),
+        [b'?', rest @ ..] => {
+            matches!(text, [c, ..] if *c != b'/') && glob_matches(rest, &text[1..])
+        }
+        [c, rest @ ..] => text.first() == Some(c) && glob_matches(rest, &text[1..]),
+    }
+}
+
+impl FindOptions {
+    fn matches(&self, filesystem: &FileSystem, sizes: &[usize], id: NodeId, depth: usize) -> bool {
+        let node = filesystem.node(id);
+        let is_dir = filesystem.is_dir(id);
+
+        if depth < self.min_depth {
+            return false;
+        }
+
+        match self.kind {
+            Some(EntryType::File) if is_dir => return false,
+            Some(EntryType::Dir) if !is_dir => return false,
+            _ => {}
+        }
+
+        if !self.extensions.is_empty() {
+            let extension = match node.name.rsplit_once('.') {
+                Some((stem, extension)) if !is_dir && !stem.is_empty() => extension,
+                _ => return false,
+            };
+            if !self.extensions.iter().any(|wanted| wanted == extension) {
+                return false;
+            }
+        }
+
+        if !self.size.iter().all(|test| test.matches(sizes[id])) {
+            return false;
+        }
+
+        if !self.path.is_empty() {
+            let path = filesystem.path(id);
+            let path = match path.trim_end_matches('/') {
+                "" => "/",
+                path => path,
+            };
+            if !self
+                .path
+                .iter()
+                .any(|pattern| glob_matches(pattern.as_bytes(), path.as_bytes()))
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Everything below the start that passes every test, in the order of a
+    /// depth-first walk by name
+    fn run(&self, filesystem: &FileSystem, sizes: &[usize]) -> Result<Vec<NodeId>, anyhow::Error> {
+        let start = filesystem
+            .lookup(&self.start)
+            .ok_or_else(|| anyhow!("{} doesn't exist", self.start))?;
+
+        let mut found = vec![];
+        let mut pending = vec![(start, 0)];
+        while let Some((id, depth)) = pending.pop() {
+            if self.matches(filesystem, sizes, id, depth) {
+                found.push(id);
+            }
+
+            if self.max_depth.is_none_or(|max_depth| depth < max_depth) {
+                let children = filesystem.children(id).collect::<Vec<NodeId>>();
+                pending.extend(children.into_iter().rev().map(|child| (child, depth + 1)));
+            }
+        }
+
+        Ok(found)
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct TreeOptions {
+    /// Show sizes as K, M and G rather than bytes
+    #[arg(long, short = 'H')]
+    human_readable: bool,
+
+    /// Don't show anything more than this many levels below the root
+    #[arg(long)]
+    max_depth: Option<usize>,
+
+    /// List the largest children first rather than by name
+    #[arg(long)]
+    sort_by_size: bool,
+
+    /// Leave out anything smaller than this many bytes
+    #[arg(long, default_value_t = 0)]
+    min_size: usize,
+}
+
+type NodeId = usize;
+
+#[derive(Debug)]
+enum NodeKind {
+    Dir { children: BTreeMap<String, NodeId> },
+    File { size: usize },
+}
+
+#[derive(Debug)]
+struct Node {
+    name: String,
+    parent: Option<NodeId>,
+    kind: NodeKind,
+}
+
+/// The device filesystem as an arena of nodes, the root always being the
+/// first. Nodes are only ever appended below existing directories, so every
+/// node comes after its parent
+#[derive(Debug)]
+struct FileSystem {
+    nodes: Vec<Node>,
+}
+
+impl FileSystem {
+    const ROOT: NodeId = 0;
+
+    fn new() -> FileSystem {
+        FileSystem {
+            nodes: vec![Node {
+                name: String::new(),
+                parent: None,
+                kind: NodeKind::Dir {
+                    children: BTreeMap::new(),
+                },
+            }],
+        }
+    }
+
+    fn node(&self, id: NodeId) -> &Node {
+        &self.nodes[id]
+    }
+
+    fn is_dir(&self, id: NodeId) -> bool {
+        matches!(self.nodes[id].kind, NodeKind::Dir { .. })
+    }
+
+    fn children(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        let children = match &self.nodes[id].kind {
+            NodeKind::Dir { children } => Some(children.values().copied()),
+            NodeKind::File { .. } => None,
+        };
+
+        children.into_iter().flatten()
+    }
+
+    fn child(&self, dir: NodeId, name: &str) -> Option<NodeId> {
+        match &self.nodes[dir].kind {
+            NodeKind::Dir { children } => children.get(name).copied(),
+            NodeKind::File { .. } => None,
+        }
+    }
+
+    /// Appends a node below `dir`, which the callers have checked has no
+    /// child of that name yet
+    fn insert(&mut self, dir: NodeId, name: &str, kind: NodeKind) -> Result<NodeId, anyhow::Error> {
+        let id = self.nodes.len();
+        match &mut self.nodes[dir].kind {
+            NodeKind::Dir { children } => children.insert(name.to_string(), id),
+            NodeKind::File { .. } => bail!("{} is not a directory", self.path(dir)),
+        };
+
+        self.nodes.push(Node {
+            name: name.to_string(),
+            parent: Some(dir),
+            kind,
+        });
+
+        Ok(id)
+    }
+
+    /// Adds a directory, or returns the existing one if it was listed before
+    fn add_dir(&mut self, dir: NodeId, name: &str) -> Result<NodeId, anyhow::Error> {
+        match self.child(dir, name) {
+            Some(id) if self.is_dir(id) => Ok(id),
+            Some(id) => bail!("{} is a file, not a directory", self.path(id)),
+            None => self.insert(
+                dir,
+                name,
+                NodeKind::Dir {
+                    children: BTreeMap::new(),
+                },
+            ),
+        }
+    }
+
+    /// Adds a file, accepting a repeated listing as long as the size agrees
+    fn add_file(&mut self, dir: NodeId, name: &str, size: usize) -> Result<NodeId, anyhow::Error> {
+        match self.child(dir, name).map(|id| (id, &self.nodes[id].kind)) {
+            Some((id, NodeKind::File { size: existing })) if *existing == size => Ok(id),
+            Some((id, NodeKind::File { size: existing })) => bail!(
+                "{} was listed as {} bytes before, now as {}",
+                self.path(id),
+                existing,
+                size
+            ),
+            Some((id, NodeKind::Dir { .. })) => {
+                bail!("{} is a directory, not a file", self.path(id))
             }
-            _ => unreachable!("Shouldn't happen"),
+            None => self.insert(dir, name, NodeKind::File { size }),
+        }
+    }
+
+    /// Absolute path of a node, directories ending in a slash
+    fn path(&self, id: NodeId) -> String {
+        let mut names = vec![];
+        let mut current = Some(id);
+        while let Some(node) = current {
+            names.push(self.nodes[node].name.as_str());
+            current = self.nodes[node].parent;
+        }
+        names.reverse();
+
+        let mut path = names.join("/");
+        if path.is_empty() || self.is_dir(id) {
+            path.push('/');
+        }
+        path
+    }
+
+    /// Total size of every node, indexed by id. Children always come after
+    /// their parents, so one backwards pass adds each size to its parent
+    /// after it has been completed
+    fn sizes(&self) -> Vec<usize> {
+        let mut sizes = self
+            .nodes
+            .iter()
+            .map(|node| match node.kind {
+                NodeKind::File { size } => size,
+                NodeKind::Dir { .. } => 0,
+            })
+            .collect::<Vec<usize>>();
+
+        for id in (1..self.nodes.len()).rev() {
+            if let Some(parent) = self.nodes[id].parent {
+                sizes[parent] += sizes[id];
+            }
+        }
+
+        sizes
+    }
+
+    /// Finds a node by its absolute path, with or without a trailing slash
+    fn lookup(&self, path: &str) -> Option<NodeId> {
+        path.strip_prefix('/')?
+            .split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(FileSystem::ROOT, |dir, name| self.child(dir, name))
+    }
+
+    fn dirs(&self) -> impl Iterator<Item = NodeId> + '_ {
+        (0..self.nodes.len()).filter(|&id| self.is_dir(id))
+    }
+}
+
+/// Formats a byte count the way `du -h` does, in powers of 1024 with one
+/// decimal below 10
+fn human_readable(size: usize) -> String {
+    const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+
+    if size < 1024 {
+        return size.to_string();
+    }
+
+    let mut scaled = size as f64 / 1024.0;
+    let mut unit = 0;
+    while scaled >= 1024.0 && unit + 1 < UNITS.len() {
+        scaled /= 1024.0;
+        unit += 1;
+    }
+
+    if scaled < 10.0 {
+        format!("{:.1}{}", scaled, UNITS[unit])
+    } else {
+        format!("{:.0}{}", scaled, UNITS[unit])
+    }
+}
+
+/// Renders the filesystem in the same indented form the puzzle uses, with
+/// every directory showing its recursive size
+fn tree_report(filesystem: &FileSystem, sizes: &[usize], options: &TreeOptions) -> String {
+    let mut report = String::new();
+    let mut pending = vec![(FileSystem::ROOT, 0)];
+
+    while let Some((id, depth)) = pending.pop() {
+        let node = filesystem.node(id);
+        let size = if options.human_readable {
+            human_readable(sizes[id])
+        } else {
+            sizes[id].to_string()
         };
+        let (name, kind) = match node.kind {
+            _ if id == FileSystem::ROOT => ("/", "dir"),
+            NodeKind::Dir { .. } => (node.name.as_str(), "dir"),
+            NodeKind::File { .. } => (node.name.as_str(), "file"),
+        };
+        let _ = writeln!(
+            report,
+            "{}- {} ({}, size={})",
+            "  ".repeat(depth),
+            name,
+            kind,
+            size
+        );
+
+        if options
+            .max_depth
+            .is_some_and(|max_depth| depth >= max_depth)
+        {
+            continue;
+        }
+
+        let mut children = filesystem
+            .children(id)
+            .filter(|&child| sizes[child] >= options.min_size)
+            .collect::<Vec<NodeId>>();
+        if options.sort_by_size {
+            // Stable, so equal sizes stay in name order
+            children.sort_by(|a, b| sizes[*b].cmp(&sizes[*a]));
+        }
 
-        Ok(File {
-            size,
-            path: String::from("/"),
-        })
+        pending.extend(children.into_iter().rev().map(|child| (child, depth + 1)));
+    }
+
+    report
+}
+
+#[derive(Debug)]
+struct DeletionPlan {
+    nodes: Vec<NodeId>,
+    freed: usize,
+}
+
+#[derive(Debug)]
+enum DeletionOutcome {
+    Found(DeletionPlan),
+    /// Even deleting everything that isn't protected wouldn't be enough
+    Impossible,
+}
+
+/// A set of byte counts up to some bound, one bit each
+#[derive(Debug, Clone)]
+struct ByteCounts {
+    words: Vec<u64>,
+    bound: usize,
+}
+
+impl ByteCounts {
+    fn new(bound: usize) -> ByteCounts {
+        ByteCounts {
+            words: vec![0; bound / 64 + 1],
+            bound,
+        }
+    }
+
+    fn contains(&self, count: usize) -> bool {
+        count <= self.bound && self.words[count / 64] & (1 << (count % 64)) != 0
+    }
+
+    fn insert(&mut self, count: usize) {
+        if count <= self.bound {
+            self.words[count / 64] |= 1 << (count % 64);
+        }
+    }
+
+    /// Adds every count of `other` increased by `offset`, dropping whatever
+    /// ends up beyond the bound
+    fn insert_shifted(&mut self, other: &ByteCounts, offset: usize) {
+        let (word_offset, bit_offset) = (offset / 64, offset % 64);
+
+        for target in word_offset..self.words.len() {
+            let source = target - word_offset;
+            let mut word = other.words[source] << bit_offset;
+            if bit_offset > 0 && source > 0 {
+                word |= other.words[source - 1] >> (64 - bit_offset);
+            }
+            self.words[target] |= word;
+        }
+
+        let spare_bits = 63 - self.bound % 64;
+        if let Some(last) = self.words.last_mut() {
+            *last &= u64::MAX >> spare_bits;
+        }
+    }
+}
+
+/// Subset sum over the tree, where each node is either deleted whole or
+/// kept, with its children then considered in turn. That way no chosen
+/// node can ever be inside another.
+///
+/// Arriving at a node with some set of byte counts deleted so far, the set
+/// after its subtree is those counts plus its size if it's deleted, along
+/// with whatever its children lead to otherwise. Only counts up to a known
+/// plan's total matter, so each set is a bitset of that size. Rather than
+/// remembering how every count was reached, the plan is traced back from
+/// the best count, recomputing a directory's intermediate sets only when
+/// the trace goes through it
+struct DeletionPlanner<'a> {
+    filesystem: &'a FileSystem,
+    sizes: &'a [usize],
+    deletable: Vec<bool>,
+    space_needed: usize,
+}
+
+impl<'a> DeletionPlanner<'a> {
+    /// Everything except the root, protected nodes with all they contain,
+    /// and whatever contains a protected node can be deleted
+    fn new(
+        filesystem: &'a FileSystem,
+        sizes: &'a [usize],
+        protected: &[NodeId],
+        space_needed: usize,
+    ) -> DeletionPlanner<'a> {
+        let mut deletable = vec![true; filesystem.nodes.len()];
+        deletable[FileSystem::ROOT] = false;
+        for &id in protected {
+            let mut current = Some(id);
+            while let Some(node) = current {
+                deletable[node] = false;
+                current = filesystem.node(node).parent;
+            }
+        }
+
+        // Parents come before their children, so one pass covers the
+        // contents of protected directories
+        let mut guarded = vec![false; filesystem.nodes.len()];
+        for &id in protected {
+            guarded[id] = true;
+        }
+        for id in 1..filesystem.nodes.len() {
+            if filesystem
+                .node(id)
+                .parent
+                .is_some_and(|parent| guarded[parent])
+            {
+                guarded[id] = true;
+                deletable[id] = false;
+            }
+        }
+
+        DeletionPlanner {
+            filesystem,
+            sizes,
+            deletable,
+            space_needed,
+        }
+    }
+
+    /// Deletes whole subtrees in preorder until enough is freed. Nothing
+    /// deleted this way is bigger than the space needed plus one node, which
+    /// bounds the byte counts worth tracking
+    fn greedy_plan(&self) -> Option<usize> {
+        let mut deleted = 0;
+        let mut pending = vec![FileSystem::ROOT];
+        while let Some(id) = pending.pop() {
+            if deleted >= self.space_needed {
+                break;
+            }
+
+            if self.deletable[id] {
+                deleted += self.sizes[id];
+            } else {
+                pending.extend(self.filesystem.children(id));
+            }
+        }
+
+        (deleted >= self.space_needed).then_some(deleted)
+    }
+
+    /// Counts that can have been deleted once the subtree of `id` is done
+    fn after(&self, id: NodeId, arriving: &ByteCounts) -> ByteCounts {
+        let mut counts = self
+            .filesystem
+            .children(id)
+            .fold(arriving.clone(), |counts, child| self.after(child, &counts));
+
+        if self.deletable[id] {
+            counts.insert_shifted(arriving, self.sizes[id]);
+        }
+
+        counts
+    }
+
+    /// Adds the nodes in the subtree of `id` that take `arriving` to
+    /// `target` deleted bytes, returning the count they start from
+    fn trace(
+        &self,
+        id: NodeId,
+        arriving: &ByteCounts,
+        target: usize,
+        plan: &mut Vec<NodeId>,
+    ) -> usize {
+        let size = self.sizes[id];
+        if self.deletable[id] && target >= size && arriving.contains(target - size) {
+            plan.push(id);
+            return target - size;
+        }
+
+        let children = self.filesystem.children(id).collect::<Vec<NodeId>>();
+        let mut intermediate = vec![arriving.clone()];
+        for &child in &children[..children.len().saturating_sub(1)] {
+            let next = self.after(child, &intermediate[intermediate.len() - 1]);
+            intermediate.push(next);
+        }
+
+        children
+            .iter()
+            .zip(intermediate.iter())
+            .rev()
+            .fold(target, |target, (&child, arriving)| {
+                self.trace(child, arriving, target, plan)
+            })
+    }
+
+    fn solve(&self) -> DeletionOutcome {
+        let Some(greedy) = self.greedy_plan() else {
+            return DeletionOutcome::Impossible;
+        };
+
+        // A single node is often a better bound than the greedy plan
+        let bound = (0..self.filesystem.nodes.len())
+            .filter(|&id| self.deletable[id] && self.sizes[id] >= self.space_needed)
+            .map(|id| self.sizes[id])
+            .fold(greedy, usize::min);
+
+        let mut start = ByteCounts::new(bound);
+        start.insert(0);
+        let reached = self.after(FileSystem::ROOT, &start);
+
+        let Some(freed) = (self.space_needed..=bound).find(|&count| reached.contains(count)) else {
+            return DeletionOutcome::Impossible;
+        };
+
+        let mut nodes = vec![];
+        self.trace(FileSystem::ROOT, &start, freed, &mut nodes);
+        nodes.reverse();
+
+        DeletionOutcome::Found(DeletionPlan { nodes, freed })
+    }
+}
+
+/// Quotes a path for the shell if it contains anything unusual
+fn shell_quote(path: &str) -> String {
+    if path
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "/._-".contains(c))
+    {
+        path.to_string()
+    } else {
+        format!("'{}'", path.replace('\'', "'\\''"))
+    }
+}
+
+/// Writes every node below `root`, which is created first. Files are only
+/// given a length, so on most filesystems they take up no actual space.
+/// Nothing that already exists is ever opened, let alone truncated, and
+/// every name is checked again so a path can't lead outside `root`
+fn materialise(filesystem: &FileSystem, root: &Path) -> Result<usize, anyhow::Error> {
+    fs::create_dir(root).with_context(|| format!("Couldn't create {}", root.display()))?;
+
+    let mut paths = vec![root.to_path_buf(); filesystem.nodes.len()];
+    let mut files = 0;
+    // Parents come before their children, so directories exist in time
+    for id in 1..filesystem.nodes.len() {
+        let node = filesystem.node(id);
+        let name = entry_name(&node.name)?;
+        let parent = node.parent.unwrap_or(FileSystem::ROOT);
+        let path = paths[parent].join(&name);
+        ensure!(
+            path.starts_with(root) && path.parent() == Some(paths[parent].as_path()),
+            "{} would end up outside {}",
+            filesystem.path(id),
+            root.display()
+        );
+
+        match node.kind {
+            NodeKind::Dir { .. } => fs::create_dir(&path),
+            NodeKind::File { size } => {
+                files += 1;
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)
+                    .and_then(|file| file.set_len(size as u64))
+            }
+        }
+        .with_context(|| format!("Couldn't create {}", path.display()))?;
+
+        paths[id] = path;
+    }
+
+    Ok(files)
+}
+
+/// Lists a real directory the way the device's terminal would, then
+/// descends into each subdirectory in turn. Entries are sorted by name and
+/// anything that is neither a file nor a directory, such as a symlink, is
+/// left out
+fn transcript(dir: &Path) -> Result<String, anyhow::Error> {
+    fn browse(dir: &Path, log: &mut String) -> Result<(), anyhow::Error> {
+        let mut entries = fs::read_dir(dir)
+            .with_context(|| format!("Couldn't list {}", dir.display()))?
+            .map(|entry| {
+                let entry = entry?;
+                let name = entry
+                    .file_name()
+                    .into_string()
+                    .map_err(|name| anyhow!("{:?} in {} isn't UTF-8", name, dir.display()))?;
+                Ok((name, entry.metadata()?))
+            })
+            .collect::<Result<Vec<_>, anyhow::Error>>()?;
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let _ = writeln!(log, "$ ls");
+        for (name, metadata) in &entries {
+            if metadata.is_dir() {
+                let _ = writeln!(log, "dir {}", name);
+            } else if metadata.is_file() {
+                let _ = writeln!(log, "{} {}", metadata.len(), name);
+            }
+        }
+
+        for (name, _) in entries.iter().filter(|(_, metadata)| metadata.is_dir()) {
+            let _ = writeln!(log, "$ cd {}", name);
+            browse(&dir.join(name), log)?;
+            let _ = writeln!(log, "$ cd ..");
+        }
+
+        Ok(())
+    }
+
+    let mut log = String::from("$ cd /\n");
+    browse(dir, &mut log)?;
+    Ok(log)
+}
+
+#[derive(Debug)]
+enum CdTarget {
+    Root,
+    Parent,
+    Child(String),
+}
+
+#[derive(Debug)]
+enum LogLine {
+    Cd(CdTarget),
+    Ls,
+    Dir(String),
+    File { name: String, size: usize },
+}
+
+/// Checks a name is a single path component, so it can't reach outside its
+/// directory once joined onto a path
+fn entry_name(name: &str) -> Result<String, anyhow::Error> {
+    ensure!(
+        !name.is_empty() && name != "." && name != ".." && !name.contains('/'),
+        "Invalid name {:?}",
+        name
+    );
+
+    Ok(name.to_string())
+}
+
+impl FromStr for LogLine {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(command) = s.strip_prefix("$ ") {
+            return match command.split_once(' ') {
+                Some(("cd", "/")) => Ok(LogLine::Cd(CdTarget::Root)),
+                Some(("cd", "..")) => Ok(LogLine::Cd(CdTarget::Parent)),
+                Some(("cd", name)) => Ok(LogLine::Cd(CdTarget::Child(entry_name(name)?))),
+                None if command == "ls" => Ok(LogLine::Ls),
+                _ => bail!("Unknown command {:?}", command),
+            };
+        }
+
+        match s.split_once(' ') {
+            Some(("dir", name)) => Ok(LogLine::Dir(entry_name(name)?)),
+            Some((size, name)) => Ok(LogLine::File {
+                name: entry_name(name)?,
+                size: size
+                    .parse()
+                    .map_err(|e| anyhow!("Invalid size {:?}: {}", size, e))?,
+            }),
+            None => bail!("Expected a command or a listing entry"),
+        }
+    }
+}
+
+impl FromStr for FileSystem {
+    type Err = anyhow::Error;
+
+    /// Replays a terminal log, tracking the working directory. Directories
+    /// entered before being listed are created on the way, and `cd ..` at
+    /// the root stays there, as it would in a shell
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filesystem = FileSystem::new();
+        let mut cwd = FileSystem::ROOT;
+        let mut listing = false;
+
+        for (number, line) in s.lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() {
+                continue;
+            }
+
+            let context = || format!("Line {}: {:?}", number + 1, line);
+            match line.parse::<LogLine>().with_context(context)? {
+                LogLine::Cd(target) => {
+                    listing = false;
+                    cwd = match target {
+                        CdTarget::Root => FileSystem::ROOT,
+                        CdTarget::Parent => filesystem.node(cwd).parent.unwrap_or(FileSystem::ROOT),
+                        CdTarget::Child(name) => {
+                            filesystem.add_dir(cwd, &name).with_context(context)?
+                        }
+                    };
+                }
+                LogLine::Ls => listing = true,
+                _ if !listing => bail!("{}: listing output without `ls`", context()),
+                LogLine::Dir(name) => {
+                    filesystem.add_dir(cwd, &name).with_context(context)?;
+                }
+                LogLine::File { name, size } => {
+                    filesystem
+                        .add_file(cwd, &name, size)
+                        .with_context(context)?;
+                }
+            }
+        }
+
+        Ok(filesystem)
     }
 }
 
 fn main() -> Result<(), anyhow::Error> {
-    // This ended up very hacky :)
-    let browse_log = std::fs::read_to_string("src/inputs/day07.txt").unwrap();
+    let args = Args::parse();
 
-    let mut commands = browse_log.lines();
-    let mut current_directory = vec![];
-    let mut directories = HashSet::new();
-    let mut files: Vec<File> = vec![];
+    if let Some(Command::Transcript { dir }) = &args.command {
+        print!("{}", transcript(dir)?);
+        return Ok(());
+    }
 
-    while let Some(command) = commands.next() {
-        if command.contains("cd ..") {
-            current_directory.pop();
-        } else if command.contains("cd ") {
-            let (_, dir) = command.split_once(" ").unwrap();
-            let dir = dir.strip_prefix("cd ").unwrap();
-            current_directory.push(dir);
-            directories.insert(current_directory.join("/"));
+    let browse_log = std::fs::read_to_string(&args.input)
+        .with_context(|| format!("Couldn't read {}", args.input.display()))?;
+    let filesystem = browse_log.parse::<FileSystem>()?;
+    let sizes = filesystem.sizes();
+
+    match &args.command {
+        Some(Command::Find(options)) => {
+            for id in options.run(&filesystem, &sizes)? {
+                println!("{:>10} {}", sizes[id], filesystem.path(id));
+            }
+            return Ok(());
         }
+        Some(Command::Materialise { into }) => {
+            let root = match into {
+                Some(into) => into.clone(),
+                None => {
+                    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+                    std::env::temp_dir().join(format!("day07-{}-{}", std::process::id(), nanos))
+                }
+            };
 
-        if command.chars().any(|c| c.is_numeric()) {
-            let mut file = command.parse::<File>().unwrap();
-            file.path = format!("{}{}", file.path, current_directory.join("/"));
-            files.push(file);
+            let files = materialise(&filesystem, &root)?;
+            println!(
+                "Wrote {} files, {} bytes in total, to {}",
+                files,
+                sizes[FileSystem::ROOT],
+                root.display()
+            );
+            return Ok(());
         }
+        Some(Command::Transcript { .. }) | None => {}
     }
 
-    let directory_sizes = &directories
-        .into_iter()
-        .map(|dir| {
-            files
-                .iter()
-                .filter(|file| file.path.contains(&dir))
-                .map(|file| file.size)
-                .sum::<usize>()
-        })
-        .collect::<Vec<usize>>();
-
-    let sum = directory_sizes
-        .into_iter()
-        .filter(|&s| s <= &100000)
+    if args.tree {
+        print!("{}", tree_report(&filesystem, &sizes, &args.tree_options));
+    }
+
+    let sum = filesystem
+        .dirs()
+        .map(|dir| sizes[dir])
+        .filter(|&size| size <= args.small_dir_limit)
         .sum::<usize>();
 
     println!(
-        "Sum of total sizes of directories that have a size of at most 100 000: {}",
-        sum
+        "Sum of total sizes of directories that have a size of at most {}: {}",
+        args.small_dir_limit, sum
     );
 
-    const SPACE_AVAILABLE: usize = 70000000;
-    let root_dir_space = directory_sizes.iter().max().unwrap();
-    let unused_space = SPACE_AVAILABLE - root_dir_space;
-    let space_needed = 30000000 - unused_space;
+    let root_dir_space = sizes[FileSystem::ROOT];
+    // Written this way round so an over-full disk still works out
+    let space_needed = args
+        .update_size
+        .saturating_add(root_dir_space)
+        .saturating_sub(args.disk_size);
+    if space_needed == 0 {
+        println!(
+            "No deletion needed, {} bytes are already free for the {} byte update",
+            args.disk_size - root_dir_space,
+            args.update_size
+        );
+        return Ok(());
+    }
 
-    let mut smallest_size_deletable = directory_sizes
-        .iter()
-        .filter(|&size| size >= &space_needed)
-        .map(|size| *size)
-        .collect::<Vec<usize>>();
+    if space_needed > root_dir_space {
+        println!(
+            "Impossible to free up {} more bytes, even deleting everything only frees {}",
+            space_needed, root_dir_space
+        );
+        return Ok(());
+    }
 
-    smallest_size_deletable.sort();
+    let smallest_size_deletable = filesystem
+        .dirs()
+        .map(|dir| sizes[dir])
+        .filter(|&size| size >= space_needed)
+        .min()
+        .ok_or_else(|| anyhow!("No directory is large enough to free up the space"))?;
 
     println!(
         "Size of smallest directory that can be deleted to free up desired space: {}",
-        smallest_size_deletable.first().unwrap()
+        smallest_size_deletable
     );
 
+    if args.plan_deletion {
+        let protected = args
+            .protected
+            .iter()
+            .map(|path| {
+                filesystem
+                    .lookup(path)
+                    .ok_or_else(|| anyhow!("Protected path {} doesn't exist", path))
+            })
+            .collect::<Result<Vec<NodeId>, _>>()?;
+
+        let planner = DeletionPlanner::new(&filesystem, &sizes, &protected, space_needed);
+        match planner.solve() {
+            DeletionOutcome::Found(plan) => {
+                println!(
+                    "Deleting {} entries frees {} bytes:",
+                    plan.nodes.len(),
+                    plan.freed
+                );
+                for id in plan.nodes {
+                    let path = filesystem.path(id);
+                    println!("rm -r {}", shell_quote(path.trim_end_matches('/')));
+                }
+            }
+            DeletionOutcome::Impossible => println!(
+                "No deletion plan frees {} bytes without touching protected paths",
+                space_needed
+            ),
+        }
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sibling_prefixes_and_cd_root_are_counted_once() {
+        let log = "\
+$ cd /
+$ ls
+dir a
+dir ab
+1 root.txt
+$ cd a
+$ ls
+10 x
+$ cd /
+$ cd ab
+$ ls
+100 y
+dir c
+$ cd c
+$ ls
+1000 z
+$ cd /
+$ ls
+dir a
+dir ab
+1 root.txt
+";
+        let filesystem = log.parse::<FileSystem>().unwrap();
+        let sizes = filesystem.sizes();
+
+        for (path, size) in [
+            ("/", 1111),
+            ("/a", 10),
+            ("/ab", 1100),
+            ("/ab/c", 1000),
+            ("/ab/c/z", 1000),
+            ("/root.txt", 1),
+        ] {
+            let id = filesystem.lookup(path).unwrap();
+            assert_eq!(sizes[id], size, "{}", path);
+        }
+        assert_eq!(filesystem.nodes.len(), 8);
+    }
+
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, bound: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % bound as u64) as usize
+        }
+    }
+
+    #[test]
+    fn shifted_byte_counts_match_a_set() {
+        let mut rng = Rng(0x2022);
+        for _ in 0..500 {
+            let bound = rng.below(300);
+            let offset = rng.below(bound + 70);
+
+            let counts = (0..rng.below(20))
+                .map(|_| rng.below(bound + 1))
+                .collect::<Vec<usize>>();
+            let existing = (0..rng.below(5))
+                .map(|_| rng.below(bound + 1))
+                .collect::<Vec<usize>>();
+
+            let mut other = ByteCounts::new(bound);
+            counts.iter().for_each(|&count| other.insert(count));
+            let mut shifted = ByteCounts::new(bound);
+            existing.iter().for_each(|&count| shifted.insert(count));
+            shifted.insert_shifted(&other, offset);
+
+            for count in 0..bound + 70 {
+                let expected = count <= bound
+                    && (existing.contains(&count)
+                        || count
+                            .checked_sub(offset)
+                            .is_some_and(|count| counts.contains(&count)));
+                assert_eq!(
+                    shifted.contains(count),
+                    expected,
+                    "{} after shifting {:?} by {} within {}",
+                    count,
+                    counts,
+                    offset,
+                    bound
+                );
+            }
+        }
+    }
+
+    fn is_inside(filesystem: &FileSystem, id: NodeId, dir: NodeId) -> bool {
+        let mut current = filesystem.node(id).parent;
+        while let Some(node) = current {
+            if node == dir {
+                return true;
+            }
+            current = filesystem.node(node).parent;
+        }
+        false
+    }
+
+    /// Smallest total over every set of deletable nodes where none is
+    /// inside another that frees at least `space_needed`
+    fn exhaustive_plan(
+        filesystem: &FileSystem,
+        sizes: &[usize],
+        deletable: &[NodeId],
+        space_needed: usize,
+    ) -> Option<usize> {
+        (0..1usize << deletable.len())
+            .map(|mask| {
+                (0..deletable.len())
+                    .filter(|bit| mask & (1 << bit) != 0)
+                    .map(|bit| deletable[bit])
+                    .collect::<Vec<NodeId>>()
+            })
+            .filter(|chosen| {
+                chosen.iter().all(|&id| {
+                    chosen
+                        .iter()
+                        .all(|&other| !is_inside(filesystem, id, other))
+                })
+            })
+            .map(|chosen| chosen.iter().map(|&id| sizes[id]).sum::<usize>())
+            .filter(|&freed| freed >= space_needed)
+            .min()
+    }
+
+    #[test]
+    fn planner_matches_exhaustive_search() {
+        let mut rng = Rng(0x7);
+        for round in 0..2000 {
+            let mut filesystem = FileSystem::new();
+            let mut dirs = vec![FileSystem::ROOT];
+            for n in 0..1 + rng.below(11) {
+                let parent = dirs[rng.below(dirs.len())];
+                let name = format!("n{}", n);
+                if rng.below(3) == 0 {
+                    dirs.push(filesystem.add_dir(parent, &name).unwrap());
+                } else {
+                    filesystem
+                        .add_file(parent, &name, 1 + rng.below(150))
+                        .unwrap();
+                }
+            }
+
+            let sizes = filesystem.sizes();
+            let protected = (1..filesystem.nodes.len())
+                .filter(|_| rng.below(6) == 0)
+                .collect::<Vec<NodeId>>();
+            let space_needed = rng.below(sizes[FileSystem::ROOT] + 20);
+
+            let planner = DeletionPlanner::new(&filesystem, &sizes, &protected, space_needed);
+            let deletable = (1..filesystem.nodes.len())
+                .filter(|&id| {
+                    protected.iter().all(|&p| {
+                        id != p && !is_inside(&filesystem, id, p) && !is_inside(&filesystem, p, id)
+                    })
+                })
+                .collect::<Vec<NodeId>>();
+            assert_eq!(
+                (1..filesystem.nodes.len())
+                    .filter(|&id| planner.deletable[id])
+                    .collect::<Vec<NodeId>>(),
+                deletable,
+                "Round {}",
+                round
+            );
+
+            let expected = exhaustive_plan(&filesystem, &sizes, &deletable, space_needed);
+            match planner.solve() {
+                DeletionOutcome::Found(plan) => {
+                    assert_eq!(Some(plan.freed), expected, "Round {}", round);
+                    assert_eq!(
+                        plan.nodes.iter().map(|&id| sizes[id]).sum::<usize>(),
+                        plan.freed,
+                        "Round {}",
+                        round
+                    );
+                    for &id in plan.nodes.iter() {
+                        assert!(deletable.contains(&id), "Round {}", round);
+                        assert!(
+                            plan.nodes
+                                .iter()
+                                .all(|&other| !is_inside(&filesystem, id, other)),
+                            "Round {}",
+                            round
+                        );
+                    }
+                }
+                DeletionOutcome::Impossible => assert_eq!(expected, None, "Round {}", round),
+            }
+        }
+    }
+}